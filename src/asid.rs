@@ -0,0 +1,160 @@
+use crate::cpu::{make_satp, satp_read, SATP_mode, MAX_HARTS};
+use crate::lock::{spin_mutex, M_lock};
+use core::arch::asm;
+
+/*
+ * Sv39 satp has 16 ASID bits, hardware may implement fewer of them
+ */
+pub const MAX_ASID_BITS: usize = 16;
+const MAX_ASID: usize = 1 << MAX_ASID_BITS;
+const ASID_MASK: usize = MAX_ASID - 1;
+
+/*
+ * ASID 0 always belongs to kernel satp
+ */
+pub const ASID_KERN: usize = 0;
+
+/*
+ * An address space holds a context, (generation << MAX_ASID_BITS) | asid. Context 0 means
+ * no ASID assigned yet. Its ASID is only valid while generation matches the allocator's,
+ * ASIDs are never given back one by one, the whole space is recycled on rollover
+ */
+pub struct asid_allocator {
+    asid_cnt: usize,
+    generation: usize,
+    used: [u64; MAX_ASID / 64],
+    /*
+     * Context each hart is running with, kept alive across rollover
+     */
+    active: [usize; MAX_HARTS],
+    reserved: [usize; MAX_HARTS],
+}
+
+pub static ASID_ALLOC: spin_mutex<asid_allocator, M_lock> = spin_mutex::new(asid_allocator::new());
+
+pub fn ctx2asid(ctx: usize) -> usize {
+    ctx & ASID_MASK
+}
+
+impl asid_allocator {
+    pub const fn new() -> Self {
+        let mut used = [0; MAX_ASID / 64];
+        used[0] = 1;
+        asid_allocator {
+            asid_cnt: 1,
+            generation: 1,
+            used,
+            active: [0; MAX_HARTS],
+            reserved: [0; MAX_HARTS],
+        }
+    }
+
+    pub fn init(&mut self, asid_bits: usize) {
+        self.asid_cnt = 1 << asid_bits.min(MAX_ASID_BITS);
+    }
+
+    pub fn get_asid_cnt(&self) -> usize {
+        self.asid_cnt
+    }
+
+    fn is_used(&self, asid: usize) -> bool {
+        self.used[asid / 64] & (1 << (asid % 64)) != 0
+    }
+
+    fn mark_used(&mut self, asid: usize) {
+        self.used[asid / 64] |= 1 << (asid % 64);
+    }
+
+    fn find_free(&self) -> Option<usize> {
+        (1..self.asid_cnt).find(|&asid| !self.is_used(asid))
+    }
+
+    /*
+     * Every ASID of old generation is free again, except the ones harts are running
+     * with right now. Those keep their number and move to new generation on next switch
+     */
+    fn rollover(&mut self) {
+        self.generation += 1;
+        self.used = [0; MAX_ASID / 64];
+        self.mark_used(ASID_KERN);
+
+        for hart in 0..MAX_HARTS {
+            self.reserved[hart] = self.active[hart];
+            if self.active[hart] != 0 {
+                self.mark_used(ctx2asid(self.active[hart]));
+            }
+        }
+    }
+
+    fn new_ctx(&mut self, old_ctx: usize) -> usize {
+        let new_gen = self.generation << MAX_ASID_BITS;
+
+        if old_ctx != 0 && self.reserved.contains(&old_ctx) {
+            let new_ctx = new_gen | ctx2asid(old_ctx);
+            for rsv in self.reserved.iter_mut().filter(|rsv| **rsv == old_ctx) {
+                *rsv = new_ctx;
+            }
+            return new_ctx;
+        }
+
+        let asid = match self.find_free() {
+            Some(asid) => asid,
+            None => {
+                self.rollover();
+                match self.find_free() {
+                    Some(asid) => asid,
+                    None => return ASID_KERN,
+                }
+            }
+        };
+        self.mark_used(asid);
+
+        (self.generation << MAX_ASID_BITS) | asid
+    }
+
+    /*
+     * hart is about to switch to an address space with context ctx. Returns the context
+     * it should run with, which differs from ctx when ctx belongs to an old generation.
+     *
+     * Without ASID support everyone runs on ASID_KERN, flush on switch covers it
+     */
+    pub fn activate(&mut self, hart: usize, ctx: usize) -> usize {
+        if self.asid_cnt <= 1 {
+            self.active[hart] = 0;
+            return ASID_KERN;
+        }
+
+        let mut new_ctx = ctx;
+        if ctx == 0 || ctx >> MAX_ASID_BITS != self.generation {
+            new_ctx = self.new_ctx(ctx);
+        }
+        self.active[hart] = new_ctx;
+
+        new_ctx
+    }
+
+    /*
+     * hart switched to kernel satp
+     */
+    pub fn deactivate(&mut self, hart: usize) {
+        self.active[hart] = 0;
+    }
+}
+
+/*
+ * ASID field of satp is WARL, bits hardware doesn't implement read back as zero. Sv39 is
+ * used for the probe since Bare satp may ignore the other fields. M-mode doesn't
+ * translate, so this is only safe before hart leaves M-mode for the first time
+ */
+pub fn probe_asid_bits() -> usize {
+    let old_satp = satp_read() as usize;
+    let probe = make_satp(SATP_mode::Sv39, ASID_MASK, 0);
+    let asid_bits;
+    unsafe {
+        asm!("csrw satp, {0}", in(reg) probe);
+        asid_bits = ((satp_read() as usize >> 44) & ASID_MASK).count_ones() as usize;
+        asm!("csrw satp, {0}", in(reg) old_satp);
+    }
+
+    asid_bits
+}
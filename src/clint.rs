@@ -1,12 +1,57 @@
 pub const CLINT_BASE: usize = 0x200_0000;
+/*
+ * Time slice in mtime ticks, 10MHz on qemu virt
+ */
+pub const DEF_TIME_QUANTUM: u64 = 0x500_000;
 
 pub struct clint_controller {
     base_addr: usize,
+    quantum: u64,
 }
 
 impl clint_controller {
     pub const fn new(base: usize) -> Self {
-        clint_controller { base_addr: base }
+        clint_controller {
+            base_addr: base,
+            quantum: DEF_TIME_QUANTUM,
+        }
+    }
+
+    pub fn set_base(&mut self, new_base: usize) {
+        self.base_addr = new_base;
+    }
+
+    pub fn get_quantum(&self) -> u64 {
+        self.quantum
+    }
+
+    pub fn set_quantum(&mut self, new_quantum: u64) {
+        self.quantum = new_quantum;
+    }
+
+    /*
+     * Next timer interrupt of hartid fires one quantum from now
+     */
+    pub fn arm_timer(&self, hartid: usize) {
+        self.set_mtimecmp(hartid, self.read_mtime() + self.quantum);
+    }
+
+    /*
+     * msip of hartid is a 32 bit register at CLINT base, only bit 0 is writable. Writing 1
+     * raises machine software interrupt(cause 3) on that hart
+     */
+    pub fn send_msip(&self, hartid: usize) {
+        let msip_base = self.base_addr as *mut u32;
+        unsafe {
+            msip_base.add(hartid).write_volatile(1);
+        }
+    }
+
+    pub fn clear_msip(&self, hartid: usize) {
+        let msip_base = self.base_addr as *mut u32;
+        unsafe {
+            msip_base.add(hartid).write_volatile(0);
+        }
     }
 
     pub fn set_mtimecmp(&self, hartid: usize, new_val: u64) {
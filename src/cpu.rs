@@ -1,9 +1,11 @@
 use crate::_stack_start;
+use crate::ecall::{trapping, S2Mop};
 use crate::KERNEL_TRAP_FRAME;
 use core::arch::asm;
 use core::ops::BitAnd;
 use core::ops::BitOr;
 use core::ptr::null_mut;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use riscv::register::{mstatus, sie, sstatus};
 
 pub const MAX_HARTS: usize = 4;
@@ -55,7 +57,6 @@ pub struct TrapFrame {
     pub hartid: usize,
     pub cur_mode: Mode,
     pub cpuid: usize,
-    pub mie_buf: usize,
 }
 
 impl TrapFrame {
@@ -68,7 +69,6 @@ impl TrapFrame {
             hartid: 0,
             cur_mode: Mode::Machine,
             cpuid: 0,
-            mie_buf: 0,
         }
     }
 
@@ -77,13 +77,15 @@ impl TrapFrame {
     }
 
     /*
-     * refresh_from() will keep origial trap_stack unchange so it only updates
+     * refresh_from() will keep origial trap_stack and satp unchange so it only updates
      * register value
      */
     pub fn refresh_from(&mut self, src: &TrapFrame) {
         let self_trap_stack = self.trap_stack;
+        let self_satp = self.satp;
         *self = *src;
         self.trap_stack = self_trap_stack;
+        self.satp = self_satp;
     }
 }
 
@@ -161,7 +163,7 @@ pub fn mtvec_read() -> usize {
         asm!("csrr {0}, mtvec", out(reg) mtvec_val);
     }
 
-    return mtvec_val;
+    mtvec_val
 }
 
 pub fn mtvec_write(mtvec_new_val: usize) {
@@ -273,21 +275,99 @@ pub fn M_cli() -> usize {
     prev_mie
 }
 
-/*
- * M_sti will only write to mie if current mie is 0
- */
 pub fn M_sti(prev_mie: usize) {
     unsafe {
-        let mie_now = mie_read();
-        if mie_now == 0 {
-            mie_write(prev_mie);
+        mie_write(prev_mie);
+    }
+}
+
+/*
+ * Depth of nested critical sections on each hart, and interrupt enables saved by the
+ * outermost one. Enables only come back when the outermost section ends, inner ones
+ * leave them masked. A slot is only touched by its own hart, with interrupts masked
+ */
+static M_OFF_DEPTH: [AtomicUsize; MAX_HARTS] = [const { AtomicUsize::new(0) }; MAX_HARTS];
+static M_OFF_SAVED: [AtomicUsize; MAX_HARTS] = [const { AtomicUsize::new(0) }; MAX_HARTS];
+static S_OFF_DEPTH: [AtomicUsize; MAX_HARTS] = [const { AtomicUsize::new(0) }; MAX_HARTS];
+static S_OFF_SAVED: [AtomicUsize; MAX_HARTS] = [const { AtomicUsize::new(0) }; MAX_HARTS];
+
+/*
+ * Time slice ran out while task was inside an S-mode critical section. Machine timer
+ * still gets through S_push_off(), so the task is left running and gives the hart up
+ * itself once the outermost section ends
+ */
+static S_RESCHED: [AtomicBool; MAX_HARTS] = [const { AtomicBool::new(false) }; MAX_HARTS];
+
+fn push_off(depth: &AtomicUsize, saved: &AtomicUsize, prev_xie: usize) {
+    if depth.fetch_add(1, Ordering::Relaxed) == 0 {
+        saved.store(prev_xie, Ordering::Relaxed);
+    }
+}
+
+/*
+ * Returns enables to restore when this was the outermost section
+ */
+fn pop_off(depth: &AtomicUsize, saved: &AtomicUsize) -> Option<usize> {
+    let old_depth = depth.load(Ordering::Relaxed);
+    assert_ne!(old_depth, 0, "pop_off() without matching push_off()");
+    depth.store(old_depth - 1, Ordering::Relaxed);
+
+    if old_depth == 1 {
+        Some(saved.load(Ordering::Relaxed))
+    } else {
+        None
+    }
+}
+
+/*
+ * M-mode only, mie is not accessible from S-mode
+ */
+pub fn M_push_off() {
+    let prev_mie = M_cli();
+    let hart = which_cpu();
+    push_off(&M_OFF_DEPTH[hart], &M_OFF_SAVED[hart], prev_mie);
+}
+
+pub fn M_pop_off() {
+    let hart = which_cpu();
+    if let Some(prev_mie) = pop_off(&M_OFF_DEPTH[hart], &M_OFF_SAVED[hart]) {
+        M_sti(prev_mie);
+    }
+}
+
+pub fn M_off_depth(hart: usize) -> usize {
+    M_OFF_DEPTH[hart].load(Ordering::Relaxed)
+}
+
+pub fn S_push_off() {
+    let prev_sie = S_cli();
+    let hart = which_cpu();
+    push_off(&S_OFF_DEPTH[hart], &S_OFF_SAVED[hart], prev_sie);
+}
+
+pub fn S_pop_off() {
+    let hart = which_cpu();
+    if let Some(prev_sie) = pop_off(&S_OFF_DEPTH[hart], &S_OFF_SAVED[hart]) {
+        S_sti(prev_sie);
+
+        if S_RESCHED[hart].swap(false, Ordering::Relaxed) && get_cpu_mode(hart) == Mode::Supervisor
+        {
+            trapping(S2Mop::YIELD, None);
         }
     }
 }
 
+pub fn S_off_depth(hart: usize) -> usize {
+    S_OFF_DEPTH[hart].load(Ordering::Relaxed)
+}
+
+pub fn S_defer_resched(hart: usize) {
+    S_RESCHED[hart].store(true, Ordering::Relaxed);
+}
+
 pub fn busy_delay(i: usize) -> usize {
     let mut ret = i;
-    for k in 0..100_000_00 {
+    for k in 0..10_000_000 {
         if k % 2 == 0 {
             ret += 3;
         } else {
@@ -0,0 +1,400 @@
+use crate::clint::CLINT_BASE;
+use crate::cpu::MAX_HARTS;
+use crate::error::{KError, KErrorType};
+use crate::new_kerror;
+use crate::plic::{MAX_INTCNT, PLIC_BASE};
+use crate::Mprintln;
+use crate::{M_UART, S_UART};
+use core::ptr;
+use fdt_parser::{Fdt, Node};
+use get_set_macro::get_set;
+
+pub const MAX_MEM_REGIONS: usize = 8;
+pub const MAX_RSV_REGIONS: usize = 16;
+pub const MAX_VIRTIO_DEVS: usize = 8;
+
+/*
+ * Fallback values are the one from qemu/hw/riscv/virt.c, they are only used when there's no
+ * device tree passed in a1 or some node is missing from it
+ */
+const DEF_UART_BASE: usize = 0x1000_0000;
+const DEF_UART_SIZE: usize = 0x100;
+const DEF_UART_IRQ: usize = 10;
+const DEF_CLINT_SIZE: usize = 0x1_0000;
+const DEF_PLIC_SIZE: usize = 0x60_0000;
+const DEF_MEM_BASE: usize = 0x8000_0000;
+const DEF_MEM_SIZE: usize = 0x800_0000;
+/*
+ * Firmware puts FDT at the end of RAM, this much is kept away from the page allocator
+ * when the blob can't even be sized
+ */
+const DEF_FDT_RSV_SIZE: usize = 0x100_0000;
+
+const FDT_MAGIC: u32 = 0xd00d_feed;
+
+const UART_COMPAT: [&str; 1] = ["ns16550a"];
+const PLIC_COMPAT: [&str; 2] = ["riscv,plic0", "sifive,plic-1.0.0"];
+const CLINT_COMPAT: [&str; 2] = ["riscv,clint0", "sifive,clint0"];
+const VIRTIO_COMPAT: [&str; 1] = ["virtio,mmio"];
+
+#[get_set(default(inline_always, vis = "pub"), get_copy, set)]
+#[derive(Clone, Copy)]
+pub struct mem_region {
+    base: usize,
+    size: usize,
+}
+
+impl mem_region {
+    pub const fn new() -> Self {
+        mem_region { base: 0, size: 0 }
+    }
+
+    pub fn end(&self) -> usize {
+        self.base + self.size
+    }
+
+    pub fn contains(&self, addr: usize) -> bool {
+        addr >= self.base && addr < self.end()
+    }
+}
+
+#[get_set(default(inline_always, vis = "pub"), get_copy, set)]
+#[derive(Clone, Copy)]
+pub struct mmio_dev {
+    base: usize,
+    size: usize,
+    irq: usize,
+}
+
+impl mmio_dev {
+    pub const fn new(base: usize, size: usize, irq: usize) -> Self {
+        mmio_dev { base, size, irq }
+    }
+}
+
+/*
+ * platform_info is filled once by BSP inside kinit(), before any allocator exists, so
+ * everything here lives in fixed size arrays
+ */
+pub struct platform_info {
+    fdt_base: usize,
+    fdt_size: usize,
+    mem: [mem_region; MAX_MEM_REGIONS],
+    mem_cnt: usize,
+    rsv: [mem_region; MAX_RSV_REGIONS],
+    rsv_cnt: usize,
+    hart_cnt: usize,
+    /*
+     * Harts device tree lists, only the first hart_cnt of them are brought up
+     */
+    dt_hart_cnt: usize,
+    uart: mmio_dev,
+    plic: mmio_dev,
+    plic_ndev: usize,
+    clint: mmio_dev,
+    virtio: [mmio_dev; MAX_VIRTIO_DEVS],
+    virtio_cnt: usize,
+}
+
+impl platform_info {
+    pub const fn new() -> Self {
+        let mut mem = [mem_region::new(); MAX_MEM_REGIONS];
+        mem[0] = mem_region {
+            base: DEF_MEM_BASE,
+            size: DEF_MEM_SIZE,
+        };
+
+        platform_info {
+            fdt_base: 0,
+            fdt_size: 0,
+            mem,
+            mem_cnt: 1,
+            rsv: [mem_region::new(); MAX_RSV_REGIONS],
+            rsv_cnt: 0,
+            hart_cnt: MAX_HARTS,
+            dt_hart_cnt: MAX_HARTS,
+            uart: mmio_dev::new(DEF_UART_BASE, DEF_UART_SIZE, DEF_UART_IRQ),
+            plic: mmio_dev::new(PLIC_BASE, DEF_PLIC_SIZE, 0),
+            plic_ndev: MAX_INTCNT - 1,
+            clint: mmio_dev::new(CLINT_BASE, DEF_CLINT_SIZE, 0),
+            virtio: [mmio_dev::new(0, 0, 0); MAX_VIRTIO_DEVS],
+            virtio_cnt: 0,
+        }
+    }
+
+    /*
+     * parse() walks the whole tree with fdt-parser. Every node we care about is optional,
+     * missing ones keep their default values
+     */
+    pub fn parse(&mut self, dtb_addr: usize) -> Result<(), KError> {
+        let dtb_ptr =
+            ptr::NonNull::new(dtb_addr as *mut u8).ok_or(new_kerror!(KErrorType::EINVAL))?;
+
+        /*
+         * Header is sized first, so blob still gets reserved if the rest fails to parse
+         */
+        self.fdt_base = dtb_addr;
+        self.fdt_size = fdt_header_size(dtb_addr).unwrap_or(0);
+
+        let fdt = Fdt::from_ptr(dtb_ptr).map_err(|_| new_kerror!(KErrorType::EINVAL))?;
+        self.fdt_size = fdt.total_size();
+
+        self.mem_cnt = 0;
+        for mem in fdt.memory() {
+            for region in mem.regions() {
+                if self.mem_cnt >= MAX_MEM_REGIONS {
+                    break;
+                }
+                self.mem[self.mem_cnt] = mem_region {
+                    base: region.address as usize,
+                    size: region.size,
+                };
+                self.mem_cnt += 1;
+            }
+        }
+        if self.mem_cnt == 0 {
+            *self = platform_info::new();
+            return Err(new_kerror!(KErrorType::ENOMEM));
+        }
+
+        self.rsv_cnt = 0;
+        for region in fdt.memory_reservation_block() {
+            self.push_rsv(region.address as usize, region.size);
+        }
+
+        /*
+         * /reserved-memory children carry their own reg, find them by level since
+         * fdt-parser only hands out the parent node by path
+         */
+        let mut rsv_level: Option<usize> = None;
+        let mut hart_cnt = 0;
+        for node in fdt.all_nodes() {
+            if let Some(lvl) = rsv_level {
+                if node.level > lvl {
+                    if let Some(regs) = node.reg() {
+                        for reg in regs {
+                            self.push_rsv(reg.address as usize, reg.size.unwrap_or(0));
+                        }
+                    }
+                    continue;
+                }
+                rsv_level = None;
+            }
+
+            if node.name() == "reserved-memory" {
+                rsv_level = Some(node.level);
+            }
+
+            if node.name().starts_with("cpu@") && is_cpu_node(&node) {
+                hart_cnt += 1;
+            }
+        }
+
+        /*
+         * UART is not ready yet, print_info() warns about harts beyond MAX_HARTS
+         */
+        if hart_cnt != 0 {
+            self.dt_hart_cnt = hart_cnt;
+            self.hart_cnt = hart_cnt.min(MAX_HARTS);
+        }
+
+        if let Some(node) = fdt.find_compatible(&UART_COMPAT).next() {
+            self.uart = node_mmio(&node, self.uart);
+        }
+
+        if let Some(node) = fdt.find_compatible(&PLIC_COMPAT).next() {
+            self.plic = node_mmio(&node, self.plic);
+            if let Some(ndev) = node.find_property("riscv,ndev") {
+                self.plic_ndev = (ndev.u32() as usize).min(MAX_INTCNT - 1);
+            }
+        }
+
+        if let Some(node) = fdt.find_compatible(&CLINT_COMPAT).next() {
+            self.clint = node_mmio(&node, self.clint);
+        }
+
+        self.virtio_cnt = 0;
+        for node in fdt.find_compatible(&VIRTIO_COMPAT) {
+            if self.virtio_cnt >= MAX_VIRTIO_DEVS {
+                break;
+            }
+            self.virtio[self.virtio_cnt] = node_mmio(&node, mmio_dev::new(0, 0, 0));
+            self.virtio_cnt += 1;
+        }
+
+        Ok(())
+    }
+
+    fn push_rsv(&mut self, base: usize, size: usize) {
+        if size == 0 || self.rsv_cnt >= MAX_RSV_REGIONS {
+            return;
+        }
+        self.rsv[self.rsv_cnt] = mem_region { base, size };
+        self.rsv_cnt += 1;
+    }
+
+    pub fn get_fdt_base(&self) -> usize {
+        self.fdt_base
+    }
+
+    pub fn get_fdt_size(&self) -> usize {
+        self.fdt_size
+    }
+
+    pub fn get_hart_cnt(&self) -> usize {
+        self.hart_cnt
+    }
+
+    pub fn get_uart(&self) -> mmio_dev {
+        self.uart
+    }
+
+    pub fn get_plic(&self) -> mmio_dev {
+        self.plic
+    }
+
+    pub fn get_plic_ndev(&self) -> usize {
+        self.plic_ndev
+    }
+
+    pub fn get_clint(&self) -> mmio_dev {
+        self.clint
+    }
+
+    pub fn mem_regions(&self) -> &[mem_region] {
+        &self.mem[..self.mem_cnt]
+    }
+
+    pub fn rsv_regions(&self) -> &[mem_region] {
+        &self.rsv[..self.rsv_cnt]
+    }
+
+    pub fn virtio_devs(&self) -> &[mmio_dev] {
+        &self.virtio[..self.virtio_cnt]
+    }
+
+    /*
+     * Memory region which holds the kernel image, this is where ZONE_NORMAL lives
+     */
+    pub fn kernel_region(&self, kern_addr: usize) -> Option<mem_region> {
+        self.mem_regions()
+            .iter()
+            .find(|region| region.contains(kern_addr))
+            .copied()
+    }
+
+    /*
+     * Memory the FDT blob occupies. Without a readable header it is the last
+     * DEF_FDT_RSV_SIZE of the memory region holding kernel
+     */
+    pub fn fdt_region(&self, kern_addr: usize) -> Option<mem_region> {
+        if self.fdt_size != 0 {
+            return Some(mem_region {
+                base: self.fdt_base,
+                size: self.fdt_size,
+            });
+        }
+
+        let kern_region = self.kernel_region(kern_addr)?;
+        let size = DEF_FDT_RSV_SIZE.min(kern_region.size);
+        Some(mem_region {
+            base: kern_region.end() - size,
+            size,
+        })
+    }
+
+    pub fn print_info(&self) {
+        Mprintln!("------------Platform Info------------");
+        Mprintln!("FDT: {:#x} -- Size: {:#x}", self.fdt_base, self.fdt_size);
+        for region in self.mem_regions() {
+            Mprintln!("Memory: {:#x} -- Size: {:#x}", region.base, region.size);
+        }
+        for region in self.rsv_regions() {
+            Mprintln!("Reserved: {:#x} -- Size: {:#x}", region.base, region.size);
+        }
+        Mprintln!("Harts: {}", self.hart_cnt);
+        if self.dt_hart_cnt > self.hart_cnt {
+            Mprintln!(
+                "WARNING: device tree lists {} harts but only {} are supported, raise MAX_HARTS \
+                 to use the rest, they stay parked",
+                self.dt_hart_cnt,
+                self.hart_cnt
+            );
+        }
+        Mprintln!(
+            "UART: {:#x} -- Size: {:#x} IRQ: {}",
+            self.uart.base,
+            self.uart.size,
+            self.uart.irq
+        );
+        Mprintln!(
+            "PLIC: {:#x} -- Size: {:#x} Sources: {}",
+            self.plic.base,
+            self.plic.size,
+            self.plic_ndev
+        );
+        Mprintln!(
+            "CLINT: {:#x} -- Size: {:#x}",
+            self.clint.base,
+            self.clint.size
+        );
+        for dev in self.virtio_devs() {
+            Mprintln!(
+                "VIRTIO: {:#x} -- Size: {:#x} IRQ: {}",
+                dev.base,
+                dev.size,
+                dev.irq
+            );
+        }
+        Mprintln!("------------Platform Info End------------");
+    }
+}
+
+fn is_cpu_node(node: &Node) -> bool {
+    match node.find_property("device_type") {
+        Some(prop) => prop.str() == "cpu",
+        None => false,
+    }
+}
+
+/*
+ * First reg entry and first interrupt cell of a node, fields that can't be found keep the
+ * value from fallback
+ */
+fn node_mmio(node: &Node, fallback: mmio_dev) -> mmio_dev {
+    let mut dev = fallback;
+
+    if let Some(reg) = node.reg().and_then(|mut regs| regs.next()) {
+        dev.base = reg.address as usize;
+        dev.size = reg.size.unwrap_or(fallback.size);
+    }
+
+    if let Some(irq) = node
+        .interrupts()
+        .and_then(|mut ints| ints.next())
+        .and_then(|mut cells| cells.next())
+    {
+        dev.irq = irq as usize;
+    }
+
+    dev
+}
+
+/*
+ * totalsize field of FDT header, both header fields are big endian
+ */
+fn fdt_header_size(dtb_addr: usize) -> Option<usize> {
+    let header = dtb_addr as *const u32;
+    let (magic, totalsize) = unsafe {
+        (
+            u32::from_be(header.read_volatile()),
+            u32::from_be(header.add(1).read_volatile()),
+        )
+    };
+
+    if magic == FDT_MAGIC && totalsize != 0 {
+        Some(totalsize as usize)
+    } else {
+        None
+    }
+}
@@ -12,6 +12,10 @@ pub enum S2Mop {
     UNBLOCK,
     CLI,
     STI,
+    SETPRIO,
+    SETAFFINITY,
+    WAIT,
+    SLEEP,
     UNDEF,
 }
 
@@ -21,6 +25,36 @@ pub enum U2Sop {
     SEND,
     RECV,
     SEND_RECV,
+    FORK,
+    EXIT,
+    WAIT,
+}
+
+/*
+ * U-mode ecall ABI: a7 holds U2Sop number, a0..a5 are arguments and a0 carries return value
+ *
+ * SEND / RECV / SEND_RECV: a0 = peer pid(IPC_ANY for RECV from anyone), a1 = peer lifeid,
+ * a2 = user address of a kmsg
+ *
+ * FORK: no argument, a0 = child pid in parent and 0 in child, a1 = child lifeid in parent
+ *
+ * EXIT: a0 = exit code, never returns
+ *
+ * WAIT: a0 = child pid, a1 = child lifeid. a0 = exit code of child once it exits, or
+ * usize::MAX if it's not a child of caller
+ */
+impl From<usize> for U2Sop {
+    fn from(op: usize) -> Self {
+        match op {
+            1 => U2Sop::SEND,
+            2 => U2Sop::RECV,
+            3 => U2Sop::SEND_RECV,
+            4 => U2Sop::FORK,
+            5 => U2Sop::EXIT,
+            6 => U2Sop::WAIT,
+            _ => U2Sop::UNDEF,
+        }
+    }
 }
 
 #[derive(Clone, Copy)]
@@ -36,8 +70,8 @@ impl ecall_args {
         ecall_args {
             sbiop: S2Mop::UNDEF,
             syscallop: U2Sop::UNDEF,
-            args: [0 as usize; 5],
-            ret: 0 as usize,
+            args: [0; 5],
+            ret: 0,
         }
     }
 
@@ -0,0 +1,295 @@
+use crate::alloc::vec::Vec;
+use crate::error::{KError, KErrorType};
+use crate::new_kerror;
+use crate::page::PAGE_SIZE;
+use crate::vm::{is_user_range, mm, EntryBits};
+use crate::{aligh_4k, aligl_4k};
+use core::mem;
+
+/*
+ * Only the part of ELF64 we need for static RISC-V executables
+ */
+const ELF_MAGIC: [u8; 4] = [0x7f, b'E', b'L', b'F'];
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const EV_CURRENT: u8 = 1;
+const ET_EXEC: u16 = 2;
+const EM_RISCV: u16 = 243;
+
+const EHDR_SZ: usize = 64;
+const PHDR_SZ: usize = 56;
+
+const PT_LOAD: u32 = 1;
+const PT_PHDR: u32 = 6;
+
+const PF_X: u32 = 1 << 0;
+const PF_W: u32 = 1 << 1;
+const PF_R: u32 = 1 << 2;
+
+const AT_NULL: usize = 0;
+const AT_PHDR: usize = 3;
+const AT_PHENT: usize = 4;
+const AT_PHNUM: usize = 5;
+const AT_PAGESZ: usize = 6;
+const AT_ENTRY: usize = 9;
+
+#[derive(Clone, Copy)]
+pub struct elf_info {
+    pub entry: usize,
+    pub phdr: usize,
+    pub phnum: usize,
+    pub brk: usize,
+}
+
+#[derive(Clone, Copy)]
+struct elf_phdr {
+    p_type: u32,
+    p_flags: u32,
+    p_offset: usize,
+    p_vaddr: usize,
+    p_filesz: usize,
+    p_memsz: usize,
+}
+
+/*
+ * Image is not guaranteed to be aligned, every field is read byte by byte with bound check.
+ * Offsets come from the image itself, so they may be anything up to usize::MAX
+ */
+fn read_bytes(image: &[u8], off: usize, len: usize) -> Result<&[u8], KError> {
+    let end = off
+        .checked_add(len)
+        .ok_or(new_kerror!(KErrorType::ENOEXEC))?;
+    image.get(off..end).ok_or(new_kerror!(KErrorType::ENOEXEC))
+}
+
+fn read_u16(image: &[u8], off: usize) -> Result<u16, KError> {
+    let bytes = read_bytes(image, off, 2)?;
+    Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+}
+
+fn read_u32(image: &[u8], off: usize) -> Result<u32, KError> {
+    let bytes = read_bytes(image, off, 4)?;
+    Ok(u32::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+fn read_u64(image: &[u8], off: usize) -> Result<usize, KError> {
+    let bytes = read_bytes(image, off, 8)?;
+    Ok(u64::from_le_bytes(bytes.try_into().unwrap()) as usize)
+}
+
+fn read_phdr(image: &[u8], off: usize) -> Result<elf_phdr, KError> {
+    read_bytes(image, off, PHDR_SZ)?;
+
+    Ok(elf_phdr {
+        p_type: read_u32(image, off)?,
+        p_flags: read_u32(image, off + 4)?,
+        p_offset: read_u64(image, off + 8)?,
+        p_vaddr: read_u64(image, off + 16)?,
+        p_filesz: read_u64(image, off + 32)?,
+        p_memsz: read_u64(image, off + 40)?,
+    })
+}
+
+fn pflags2bits(p_flags: u32) -> i64 {
+    let mut bits = EntryBits::User.val();
+
+    /*
+     * W without R is a reserved encoding in Sv39
+     */
+    if p_flags & (PF_R | PF_W) != 0 {
+        bits |= EntryBits::Read.val();
+    }
+    if p_flags & PF_W != 0 {
+        bits |= EntryBits::Write.val();
+    }
+    if p_flags & PF_X != 0 {
+        bits |= EntryBits::Execute.val();
+    }
+
+    bits
+}
+
+/*
+ * load_elf() maps every PT_LOAD segment of image into umm and copies file content into it.
+ * bss part is free, every page is zero filled when it is first touched
+ */
+pub fn load_elf(umm: &mut mm, image: &[u8]) -> Result<elf_info, KError> {
+    if image.len() < EHDR_SZ
+        || image.get(0..4) != Some(&ELF_MAGIC[..])
+        || image.get(4) != Some(&ELFCLASS64)
+        || image.get(5) != Some(&ELFDATA2LSB)
+        || image.get(6) != Some(&EV_CURRENT)
+    {
+        return Err(new_kerror!(KErrorType::ENOEXEC));
+    }
+
+    if read_u16(image, 16)? != ET_EXEC || read_u16(image, 18)? != EM_RISCV {
+        return Err(new_kerror!(KErrorType::ENOEXEC));
+    }
+
+    let entry = read_u64(image, 24)?;
+    let phoff = read_u64(image, 32)?;
+    let phentsize = read_u16(image, 54)? as usize;
+    let phnum = read_u16(image, 56)? as usize;
+
+    if phentsize != PHDR_SZ || phnum == 0 {
+        return Err(new_kerror!(KErrorType::ENOEXEC));
+    }
+
+    let mut info = elf_info {
+        entry,
+        phdr: 0,
+        phnum,
+        brk: 0,
+    };
+
+    for idx in 0..phnum {
+        let phdr_off = idx
+            .checked_mul(PHDR_SZ)
+            .and_then(|off| off.checked_add(phoff))
+            .ok_or(new_kerror!(KErrorType::ENOEXEC))?;
+        let phdr = read_phdr(image, phdr_off)?;
+
+        if phdr.p_type == PT_PHDR {
+            info.phdr = phdr.p_vaddr;
+        }
+
+        if phdr.p_type != PT_LOAD || phdr.p_memsz == 0 {
+            continue;
+        }
+
+        let seg_end = phdr
+            .p_vaddr
+            .checked_add(phdr.p_memsz)
+            .ok_or(new_kerror!(KErrorType::ENOEXEC))?;
+        let file_end = phdr
+            .p_offset
+            .checked_add(phdr.p_filesz)
+            .ok_or(new_kerror!(KErrorType::ENOEXEC))?;
+
+        if phdr.p_filesz > phdr.p_memsz
+            || file_end > image.len()
+            || !is_user_range(phdr.p_vaddr, seg_end)
+        {
+            return Err(new_kerror!(KErrorType::ENOEXEC));
+        }
+
+        load_segment(umm, &phdr, seg_end)?;
+        umm.load_user(phdr.p_vaddr, &image[phdr.p_offset..file_end])?;
+
+        /*
+         * Program headers are normally inside first PT_LOAD when there's no PT_PHDR
+         */
+        if info.phdr == 0 && phoff >= phdr.p_offset && phoff < file_end {
+            info.phdr = phdr.p_vaddr + (phoff - phdr.p_offset);
+        }
+
+        info.brk = info.brk.max(aligh_4k!(seg_end));
+    }
+
+    if info.brk == 0 || entry == usize::MAX || !is_user_range(entry, entry + 1) {
+        return Err(new_kerror!(KErrorType::ENOEXEC));
+    }
+
+    umm.set_heap_end(info.brk);
+
+    Ok(info)
+}
+
+/*
+ * Neighbour segments may share one page(e.g. .rodata and .bss), that page is already
+ * mapped by previous segment so we only widen its permission and start new vma after it
+ */
+fn load_segment(umm: &mut mm, phdr: &elf_phdr, seg_end: usize) -> Result<(), KError> {
+    let bits = pflags2bits(phdr.p_flags);
+    let mut vm_begin = aligl_4k!(phdr.p_vaddr);
+    let vm_end = aligh_4k!(seg_end);
+
+    while vm_begin < vm_end {
+        let old_bits = match umm.get_vma(vm_begin) {
+            Some(vma) => vma.get_flags() as i64,
+            None => break,
+        };
+        umm.protect(vm_begin, vm_begin + PAGE_SIZE, old_bits | bits)?;
+        vm_begin += PAGE_SIZE;
+    }
+
+    if vm_begin < vm_end {
+        umm.map_lazy(vm_begin, vm_end, bits)?;
+    }
+
+    Ok(())
+}
+
+/*
+ * Stack layout right after setup_user_stack(), growing down from UTASK_STACK_TOP:
+ *
+ *      argv strings
+ *      (padding to 16 bytes)
+ *      auxv pairs, ended by AT_NULL
+ *      envp NULL
+ *      argv NULL
+ *      argv[argc - 1] .. argv[0]
+ *      argc                            <- sp
+ */
+pub fn setup_user_stack(
+    umm: &mut mm,
+    info: &elf_info,
+    stack_top: usize,
+    stack_sz: usize,
+    argv: &[&str],
+) -> Result<usize, KError> {
+    umm.map_lazy(
+        stack_top - stack_sz,
+        stack_top,
+        EntryBits::UserReadWrite.val(),
+    )?;
+    umm.set_stack_base(stack_top);
+
+    let mut auxv: Vec<usize> = Vec::new();
+    if info.phdr != 0 {
+        auxv.extend_from_slice(&[AT_PHDR, info.phdr]);
+    }
+    auxv.extend_from_slice(&[
+        AT_PHENT, PHDR_SZ, AT_PHNUM, info.phnum, AT_PAGESZ, PAGE_SIZE, AT_ENTRY, info.entry,
+        AT_NULL, 0,
+    ]);
+
+    /*
+     * argc, argv pointers and NULL, envp NULL, then auxv. Everything is sized up before
+     * the first copy, argv comes from caller and may be as big as it likes
+     */
+    let words_sz = (argv.len() + 3 + auxv.len()) * mem::size_of::<usize>();
+    let strs_sz = argv
+        .iter()
+        .try_fold(0usize, |sz, arg| sz.checked_add(arg.len() + 1))
+        .ok_or(new_kerror!(KErrorType::ENOMEM))?;
+    match strs_sz.checked_add(words_sz + 16) {
+        Some(tot_sz) if tot_sz <= stack_sz => {}
+        _ => return Err(new_kerror!(KErrorType::ENOMEM)),
+    }
+
+    let mut sp = stack_top;
+    let mut argv_ptrs: Vec<usize> = Vec::new();
+
+    for arg in argv.iter() {
+        sp -= arg.len() + 1;
+        umm.copy_to_user(sp, arg.as_bytes())?;
+        umm.copy_to_user(sp + arg.len(), &[0])?;
+        argv_ptrs.push(sp);
+    }
+
+    let mut words: Vec<usize> = Vec::new();
+    words.push(argv_ptrs.len());
+    words.extend_from_slice(&argv_ptrs);
+    words.push(0);
+    words.push(0);
+    words.extend_from_slice(&auxv);
+
+    sp = (sp - words_sz) & !0xf;
+    for (idx, word) in words.iter().enumerate() {
+        umm.copy_to_user(sp + idx * mem::size_of::<usize>(), &word.to_le_bytes())?;
+    }
+
+    Ok(sp)
+}
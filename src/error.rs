@@ -13,6 +13,7 @@ pub enum KErrorType {
     EINVAL,
     ENOMEM,
     ENOSYS,
+    ENOEXEC,
 }
 
 pub struct KError {
@@ -45,6 +46,7 @@ impl fmt::Display for KError {
             KErrorType::EINVAL => "EINVAL",
             KErrorType::ENOMEM => "ENOMEM",
             KErrorType::ENOSYS => "ENOSYS",
+            KErrorType::ENOEXEC => "ENOEXEC",
         };
 
         write!(
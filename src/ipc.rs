@@ -0,0 +1,291 @@
+use crate::cpu::TrapFrame;
+use crate::error::{KError, KErrorType};
+use crate::kthread::{task_pool, task_state, task_struct, task_typ};
+use crate::lock::{spin_mutex, M_lock};
+use crate::new_kerror;
+use crate::pid::task_handle;
+use alloc::vec::Vec;
+use core::mem;
+use core::slice;
+
+/*
+ * Synchronous rendezvous message passing, tasks are addressed by (pid, lifeid). Nothing is
+ * buffered inside kernel, message goes from sender's buffer to receiver's buffer once both
+ * sides are in place
+ */
+pub const IPC_ANY: usize = usize::MAX;
+pub const IPC_PAYLOAD_WORDS: usize = 6;
+
+pub const IPC_OK: usize = 0;
+pub const IPC_ERR: usize = usize::MAX;
+
+/*
+ * Held from looking at peer's ipc state until both sides are blocked or woken, so two
+ * harts can't miss each other's rendezvous or both deliver into one receiver. ipc state
+ * of every task is only touched under it. exit_current() turns a task into Zombie under
+ * it as well, a peer found alive here can't be reaped before the lock is gone.
+ * Taken after EXIT_LOCK and before any runq lock
+ */
+pub static IPC_LOCK: spin_mutex<(), M_lock> = spin_mutex::new(());
+
+/*
+ * m_source and m_lifeid are always filled by kernel, whatever sender put there is ignored
+ */
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct kmsg {
+    pub m_source: usize,
+    pub m_lifeid: usize,
+    pub m_payload: [usize; IPC_PAYLOAD_WORDS],
+}
+
+impl kmsg {
+    pub const fn new() -> Self {
+        kmsg {
+            m_source: 0,
+            m_lifeid: 0,
+            m_payload: [0; IPC_PAYLOAD_WORDS],
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum ipc_wait {
+    NONE,
+    SENDING(usize, usize),
+    RECEIVING(usize, usize),
+}
+
+#[derive(Clone, Copy)]
+pub struct ipc_state {
+    wait: ipc_wait,
+    msg_addr: usize,
+    /*
+     * SEND_RECV caller turns into a receiver of the same peer once its message is taken
+     */
+    reply_pending: bool,
+}
+
+impl ipc_state {
+    pub const fn new() -> Self {
+        ipc_state {
+            wait: ipc_wait::NONE,
+            msg_addr: 0,
+            reply_pending: false,
+        }
+    }
+}
+
+/*
+ * What syscall handler should do with current task after an IPC call
+ */
+pub enum ipc_result {
+    Done,
+    Blocked,
+}
+
+fn msg_bytes(msg: &kmsg) -> &[u8] {
+    unsafe { slice::from_raw_parts(msg as *const kmsg as *const u8, mem::size_of::<kmsg>()) }
+}
+
+fn msg_bytes_mut(msg: &mut kmsg) -> &mut [u8] {
+    unsafe { slice::from_raw_parts_mut(msg as *mut kmsg as *mut u8, mem::size_of::<kmsg>()) }
+}
+
+fn peer_match(want: (usize, usize), peer: (usize, usize)) -> bool {
+    want.0 == IPC_ANY || want == peer
+}
+
+impl task_struct {
+    pub fn get_ipc_wait(&self) -> ipc_wait {
+        self.get_ipc().wait
+    }
+
+    fn read_msg(&mut self, addr: usize) -> Result<kmsg, KError> {
+        let mut msg = kmsg::new();
+        self.get_mm()
+            .copy_from_user(addr, msg_bytes_mut(&mut msg))?;
+        Ok(msg)
+    }
+
+    fn write_msg(&mut self, addr: usize, msg: &kmsg) -> Result<(), KError> {
+        self.get_mm().copy_to_user(addr, msg_bytes(msg))
+    }
+
+    fn ipc_block(&mut self, wait: ipc_wait, msg_addr: usize, reply: bool) {
+        let ipc = self.get_ipc_mut();
+        ipc.wait = wait;
+        ipc.msg_addr = msg_addr;
+        ipc.reply_pending = reply;
+        self.set_state(task_state::Block);
+    }
+
+    /*
+     * Finish IPC of a blocked partner, ret goes into its a0 since it will resume right after
+     * ecall. Caller still has to make it Ready through task_pool::set_state_by_pid()
+     */
+    fn ipc_wake(&mut self, ret: usize) {
+        *self.get_ipc_mut() = ipc_state::new();
+        set_syscall_ret(self.get_trap_frame_mut(), ret);
+    }
+}
+
+impl task_pool {
+    /*
+     * Caller must have saved current task from KERNEL_TRAP_FRAME before calling any of these,
+     * a blocked current task is resumed from its own trap frame later
+     */
+    pub fn ipc_send(
+        &mut self,
+        cpuid: usize,
+        dst: (usize, usize),
+        msg_addr: usize,
+        reply: bool,
+    ) -> Result<ipc_result, KError> {
+        let me: (usize, usize) = self.current_id(cpuid)?.into();
+        if dst == me {
+            return Err(new_kerror!(KErrorType::EINVAL));
+        }
+        let _ipc_guard = IPC_LOCK.lock();
+
+        let mut msg = self
+            .current_mut(cpuid)
+            .ok_or(new_kerror!(KErrorType::EINVAL))?
+            .read_msg(msg_addr)?;
+        msg.m_source = me.0;
+        msg.m_lifeid = me.1;
+
+        let receiver = self
+            .task_by_pid(dst.0, dst.1)
+            .ok_or(new_kerror!(KErrorType::EFAULT))?;
+        if !matches!(receiver.get_typ(), task_typ::USER) {
+            return Err(new_kerror!(KErrorType::EINVAL));
+        }
+        if matches!(receiver.get_state(), task_state::Zombie) {
+            return Err(new_kerror!(KErrorType::EFAULT));
+        }
+
+        let receiver_ready = match receiver.get_ipc_wait() {
+            ipc_wait::RECEIVING(src_pid, src_lifeid) => peer_match((src_pid, src_lifeid), me),
+            _ => false,
+        };
+
+        if receiver_ready {
+            let recv_addr = receiver.get_ipc().msg_addr;
+            let write_res = receiver.write_msg(recv_addr, &msg);
+            match write_res {
+                Ok(()) => receiver.ipc_wake(IPC_OK),
+                Err(_) => receiver.ipc_wake(IPC_ERR),
+            }
+            self.set_state_by_pid(dst.0, dst.1, task_state::Ready)?;
+            write_res?;
+
+            if reply {
+                let cur = self.current_mut(cpuid).unwrap();
+                cur.ipc_block(ipc_wait::RECEIVING(dst.0, dst.1), msg_addr, false);
+                return Ok(ipc_result::Blocked);
+            }
+            Ok(ipc_result::Done)
+        } else {
+            let cur = self.current_mut(cpuid).unwrap();
+            cur.ipc_block(ipc_wait::SENDING(dst.0, dst.1), msg_addr, reply);
+            Ok(ipc_result::Blocked)
+        }
+    }
+
+    pub fn ipc_recv(
+        &mut self,
+        cpuid: usize,
+        src: (usize, usize),
+        msg_addr: usize,
+    ) -> Result<ipc_result, KError> {
+        let me: (usize, usize) = self.current_id(cpuid)?.into();
+        if src == me {
+            return Err(new_kerror!(KErrorType::EINVAL));
+        }
+        let _ipc_guard = IPC_LOCK.lock();
+
+        let sender = self.find_sender(me, src);
+        match sender {
+            Some((sender_pid, sender_lifeid)) => {
+                let sender = self.task_by_pid(sender_pid, sender_lifeid).unwrap();
+                let send_addr = sender.get_ipc().msg_addr;
+                let reply = sender.get_ipc().reply_pending;
+                let mut msg = match sender.read_msg(send_addr) {
+                    Ok(msg) => msg,
+                    Err(e) => {
+                        sender.ipc_wake(IPC_ERR);
+                        self.set_state_by_pid(sender_pid, sender_lifeid, task_state::Ready)?;
+                        return Err(e);
+                    }
+                };
+
+                if reply {
+                    sender.ipc_block(ipc_wait::RECEIVING(me.0, me.1), send_addr, false);
+                } else {
+                    sender.ipc_wake(IPC_OK);
+                    self.set_state_by_pid(sender_pid, sender_lifeid, task_state::Ready)?;
+                }
+
+                msg.m_source = sender_pid;
+                msg.m_lifeid = sender_lifeid;
+                self.current_mut(cpuid).unwrap().write_msg(msg_addr, &msg)?;
+                Ok(ipc_result::Done)
+            }
+            None => {
+                let cur = self
+                    .current_mut(cpuid)
+                    .ok_or(new_kerror!(KErrorType::EINVAL))?;
+                cur.ipc_block(ipc_wait::RECEIVING(src.0, src.1), msg_addr, false);
+                Ok(ipc_result::Blocked)
+            }
+        }
+    }
+
+    /*
+     * gone is exiting, nobody blocked on it is ever going to be matched. Its senders and
+     * receivers, including SEND_RECV callers waiting for the reply, fail with IPC_ERR.
+     * Caller holds IPC_LOCK
+     */
+    pub fn ipc_peer_gone(&mut self, gone: task_handle) {
+        let gone: (usize, usize) = gone.into();
+        let mut waiters: Vec<(usize, usize)> = Vec::new();
+        self.for_each_task(|task| match task.get_ipc_wait() {
+            ipc_wait::SENDING(pid, lifeid) | ipc_wait::RECEIVING(pid, lifeid)
+                if (pid, lifeid) == gone =>
+            {
+                task.ipc_wake(IPC_ERR);
+                waiters.push((task.get_pid(), task.get_lifeid()));
+            }
+            _ => {}
+        });
+
+        for (pid, lifeid) in waiters {
+            let _ = self.set_state_by_pid(pid, lifeid, task_state::Ready);
+        }
+    }
+
+    /*
+     * First task blocked on sending to `me` which `src` accepts. Caller holds IPC_LOCK
+     */
+    fn find_sender(&mut self, me: (usize, usize), src: (usize, usize)) -> Option<(usize, usize)> {
+        let mut found: Option<(usize, usize)> = None;
+        self.for_each_task(|task| {
+            if found.is_none() && task.get_ipc_wait() == ipc_wait::SENDING(me.0, me.1) {
+                let peer = (task.get_pid(), task.get_lifeid());
+                if peer_match(src, peer) {
+                    found = Some(peer);
+                }
+            }
+        });
+        found
+    }
+}
+
+/*
+ * Return value of a syscall which finished without blocking goes straight into a0 of
+ * the frame m_trap restores from
+ */
+pub fn set_syscall_ret(frame: &mut TrapFrame, ret: usize) {
+    frame.regs[10] = ret;
+}
@@ -0,0 +1,198 @@
+use crate::cpu::{get_cpu_mode, which_cpu, M_cli, Mode, MAX_HARTS};
+use crate::error::{KError, KErrorType};
+use crate::lock::{spin_mutex, Critical_Area};
+use crate::new_kerror;
+use crate::tlb::{tlb_ack, tlb_req};
+use crate::Mprintln;
+use crate::{CLINT, PLATFORM};
+use crate::{M_UART, S_UART};
+use core::arch::asm;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use ringbuffer::{ConstGenericRingBuffer, RingBuffer};
+
+pub const IPI_QUEUE_SZ: usize = 16;
+
+/*
+ * Every message sits in target hart's mailbox until its msip handler drains it, msip
+ * itself only tells "there's something in the mailbox"
+ */
+#[derive(Clone, Copy)]
+pub enum ipi_msg {
+    /*
+     * Something became runnable on target hart, make it pick again
+     */
+    RESCHED,
+    /*
+     * Flush the range from target's TLB and ack the sender
+     */
+    TLB_SHOOTDOWN(tlb_req),
+    /*
+     * Run func(arg) in M-mode on target hart, with interrupts off
+     */
+    CALL(fn(usize), usize),
+    HALT,
+}
+
+type ipi_mailbox = ConstGenericRingBuffer<ipi_msg, IPI_QUEUE_SZ>;
+
+/*
+ * Critical_Area so a S-mode sender can't be interrupted by its own hart's msip handler
+ * while it holds a mailbox
+ */
+static IPI_MAILBOX: [spin_mutex<ipi_mailbox, Critical_Area>; MAX_HARTS] =
+    [const { spin_mutex::new(ipi_mailbox::new::<IPI_QUEUE_SZ>()) }; MAX_HARTS];
+
+/*
+ * Bit n is set once hart n can take msip, anything waiting for an answer only targets
+ * these harts
+ */
+static HART_ONLINE: AtomicUsize = AtomicUsize::new(0);
+
+pub fn set_online(hart: usize) {
+    HART_ONLINE.fetch_or(1 << hart, Ordering::AcqRel);
+}
+
+pub fn online_harts() -> usize {
+    HART_ONLINE.load(Ordering::Acquire)
+}
+
+/*
+ * Callable from both M-mode and S-mode, CLINT is identity mapped in kernel page table
+ */
+pub fn send_ipi(hartid: usize, msg: ipi_msg) -> Result<(), KError> {
+    if hartid >= unsafe { PLATFORM.get_hart_cnt() } {
+        return Err(new_kerror!(KErrorType::EINVAL));
+    }
+
+    let mut mailbox = IPI_MAILBOX[hartid].lock();
+    if mailbox.is_full() {
+        return Err(new_kerror!(KErrorType::ENOMEM));
+    }
+    mailbox.push(msg);
+    drop(mailbox);
+
+    unsafe {
+        CLINT.send_msip(hartid);
+    }
+
+    Ok(())
+}
+
+/*
+ * Send msg to every online hart except the calling one. Returns number of harts reached
+ */
+pub fn broadcast_ipi(msg: ipi_msg) -> Result<usize, KError> {
+    let self_id = which_cpu();
+    let online = online_harts();
+    let mut sent = 0;
+    for hartid in 0..MAX_HARTS {
+        if hartid == self_id || online & (1 << hartid) == 0 {
+            continue;
+        }
+        send_ipi(hartid, msg)?;
+        sent += 1;
+    }
+
+    Ok(sent)
+}
+
+/*
+ * Machine software interrupt handler. msip is cleared before draining so a message
+ * pushed meanwhile raises a new interrupt instead of getting lost.
+ *
+ * Returns true when a RESCHED was among the messages, scheduling is left to the caller
+ * since it owns the trap context
+ */
+pub fn ipi_handler(hart: usize) -> bool {
+    unsafe {
+        CLINT.clear_msip(hart);
+    }
+
+    drain_mailbox(hart)
+}
+
+/*
+ * For code spinning on other harts with machine interrupts off, in M-mode or in S-mode
+ * with them pushed off. Shootdowns are always served. CALL and HALT only in M-mode,
+ * they are promised to run there. RESCHED needs the trap context. Whatever is not
+ * served stays in the mailbox in its order and msip is raised again, ipi_handler()
+ * takes it once interrupts are back
+ */
+pub fn ipi_poll(hart: usize) {
+    let in_m = matches!(get_cpu_mode(hart), Mode::Machine | Mode::Machine_IRH);
+    let mut taken: [Option<ipi_msg>; IPI_QUEUE_SZ] = [None; IPI_QUEUE_SZ];
+    let mut left = false;
+
+    let mut mailbox = IPI_MAILBOX[hart].lock();
+    for slot in taken.iter_mut().take(mailbox.len()) {
+        let msg = match mailbox.dequeue() {
+            Some(msg) => msg,
+            None => break,
+        };
+
+        let servable = match msg {
+            ipi_msg::TLB_SHOOTDOWN(_) => true,
+            ipi_msg::CALL(..) | ipi_msg::HALT => in_m,
+            ipi_msg::RESCHED => false,
+        };
+        if servable {
+            *slot = Some(msg);
+        } else {
+            mailbox.push(msg);
+            left = true;
+        }
+    }
+    drop(mailbox);
+
+    for msg in taken.into_iter().flatten() {
+        serve_msg(hart, msg);
+    }
+
+    if left {
+        unsafe {
+            CLINT.send_msip(hart);
+        }
+    }
+}
+
+fn drain_mailbox(hart: usize) -> bool {
+    let mut resched = false;
+    loop {
+        let msg = match IPI_MAILBOX[hart].lock().dequeue() {
+            Some(msg) => msg,
+            None => break,
+        };
+        resched |= serve_msg(hart, msg);
+    }
+
+    resched
+}
+
+/*
+ * M-mode only except for TLB_SHOOTDOWN, mailbox lock must not be held since CALL may
+ * send IPIs itself. True for RESCHED
+ */
+fn serve_msg(hart: usize, msg: ipi_msg) -> bool {
+    match msg {
+        ipi_msg::RESCHED => {
+            return true;
+        }
+        ipi_msg::TLB_SHOOTDOWN(req) => {
+            tlb_ack(&req);
+        }
+        ipi_msg::CALL(func, arg) => {
+            func(arg);
+        }
+        ipi_msg::HALT => {
+            Mprintln!("CPU#{} halted by IPI", hart);
+            M_cli();
+            loop {
+                unsafe {
+                    asm!("wfi");
+                }
+            }
+        }
+    }
+
+    false
+}
@@ -1,11 +1,12 @@
 use crate::error::{KError, KErrorType};
 use crate::vm::PageTable;
 use crate::zone::{kfree_page, kmalloc_page, zone_type};
+use core::ptr;
 //TODO: rewrite whole thing, put them into a struct
 
-static mut KHEAP_START: *mut u8 = 0 as *mut u8;
+static mut KHEAP_START: *mut u8 = ptr::null_mut();
 static mut KHEAP_PGCNT: usize = 256;
-static mut KMEM_PAGE_TABLE: *mut PageTable = 0 as *mut PageTable;
+static mut KMEM_PAGE_TABLE: *mut PageTable = ptr::null_mut();
 static mut KERN_SATP: u64 = 0;
 
 pub fn init() -> Result<(), KError> {
@@ -20,6 +21,7 @@ pub fn init() -> Result<(), KError> {
          */
         KHEAP_START = kmalloc_page(zone_type::ZONE_NORMAL, KHEAP_PGCNT)?;
         KMEM_PAGE_TABLE = kmalloc_page(zone_type::ZONE_NORMAL, 1)? as *mut PageTable;
+        KMEM_PAGE_TABLE.write_bytes(0, 1);
     }
 
     Ok(())
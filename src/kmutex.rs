@@ -0,0 +1,135 @@
+use crate::cpu::which_cpu;
+use crate::ksemaphore::kt_semaphore;
+use crate::kthread::{get_ktpid_lifeid, INVAL_KTHREADS_PID};
+use crate::lock::{spin_mutex, S_lock};
+use crate::pid::task_handle;
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+
+/*
+ * Sleeping mutex for kernel threads. Waiters are parked in the underlying kt_semaphore
+ * instead of spinning, interrupt enables are left alone
+ */
+pub struct kt_mutex<T> {
+    sem: kt_semaphore,
+    owner: spin_mutex<Option<task_handle>, S_lock>,
+    dat: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Sync for kt_mutex<T> {}
+unsafe impl<T: Send> Send for kt_mutex<T> {}
+
+fn current_handle() -> task_handle {
+    let (pid, lifeid) = get_ktpid_lifeid(which_cpu()).unwrap_or((INVAL_KTHREADS_PID, 0));
+    assert_ne!(pid, INVAL_KTHREADS_PID);
+    assert_ne!(lifeid, 0);
+
+    task_handle::new(pid, lifeid)
+}
+
+impl<T> kt_mutex<T> {
+    pub const fn new(dat: T) -> Self {
+        Self {
+            sem: kt_semaphore::new(1),
+            owner: spin_mutex::new(None),
+            dat: UnsafeCell::new(dat),
+        }
+    }
+
+    /*
+     * Owner locking again would sleep on itself forever, that's a bug in caller
+     */
+    pub fn lock(&self) -> kt_mutex_guard<'_, T> {
+        let me = current_handle();
+        if *self.owner.lock() == Some(me) {
+            panic!("kt_mutex locked twice by task#{}", me.pid());
+        }
+
+        self.sem.wait();
+        *self.owner.lock() = Some(me);
+
+        kt_mutex_guard { mutex: self }
+    }
+
+    pub fn get_owner(&self) -> Option<task_handle> {
+        *self.owner.lock()
+    }
+}
+
+pub struct kt_mutex_guard<'a, T> {
+    mutex: &'a kt_mutex<T>,
+}
+
+impl<T> Drop for kt_mutex_guard<'_, T> {
+    fn drop(&mut self) {
+        *self.mutex.owner.lock() = None;
+        self.mutex.sem.signal(Some(which_cpu()));
+    }
+}
+
+impl<T> Deref for kt_mutex_guard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.mutex.dat.get() }
+    }
+}
+
+impl<T> DerefMut for kt_mutex_guard<'_, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        unsafe { &mut *self.mutex.dat.get() }
+    }
+}
+
+/*
+ * Condition variable paired with a kt_mutex. Waiters sleep on a semaphore starting at
+ * 0, waiters counts the ones who haven't been notified yet, so a notify can't get lost
+ * between a waiter dropping the mutex and going to sleep
+ */
+pub struct kt_condvar {
+    sem: kt_semaphore,
+    waiters: spin_mutex<usize, S_lock>,
+}
+
+impl kt_condvar {
+    pub const fn new() -> Self {
+        Self {
+            sem: kt_semaphore::new(0),
+            waiters: spin_mutex::new(0),
+        }
+    }
+
+    /*
+     * Drop the mutex, sleep until notified and take the mutex back. Wakeups don't carry
+     * the condition, caller checks it again in a loop
+     */
+    pub fn wait<'a, T>(&self, guard: kt_mutex_guard<'a, T>) -> kt_mutex_guard<'a, T> {
+        let mutex = guard.mutex;
+        *self.waiters.lock() += 1;
+        drop(guard);
+
+        self.sem.wait();
+
+        mutex.lock()
+    }
+
+    pub fn notify_one(&self) {
+        let mut waiters = self.waiters.lock();
+        if *waiters > 0 {
+            *waiters -= 1;
+            drop(waiters);
+            self.sem.signal(Some(which_cpu()));
+        }
+    }
+
+    pub fn notify_all(&self) {
+        let mut waiters = self.waiters.lock();
+        let woken = *waiters;
+        *waiters = 0;
+        drop(waiters);
+
+        for _ in 0..woken {
+            self.sem.signal(Some(which_cpu()));
+        }
+    }
+}
@@ -1,76 +1,149 @@
 use crate::alloc::boxed::Box;
+use crate::alloc::collections::VecDeque;
 use crate::alloc::vec::Vec;
 use crate::cpu::{get_cpu_mode, Mode};
 use crate::ecall::{trapping, S2Mop};
 use crate::error::{KError, KErrorType};
 use crate::kthread::INVAL_KTHREADS_PID;
 use crate::kthread::{get_ktpid_lifeid, task_state};
+use crate::ktimer::{duration2ticks, read_ticks, sleep_until};
 use crate::lock::spin_mutex;
 use crate::lock::{Critical_Area, M_lock, S_lock};
 use crate::new_kerror;
+use crate::pid::task_handle;
 use crate::task_struct;
 use crate::which_cpu;
 use crate::Mprintln;
 use crate::KTHREAD_POOL;
 use crate::{M_UART, S_UART};
+use core::time::Duration;
+
+/*
+ * Count and waiters sit under one lock, so a signal on one hart and a wait on another
+ * always agree on whether somebody has to sleep. Waiters are woken in FIFO order.
+ *
+ * A waiter only leaves wait() once signal() took it off wait_q, any other wakeup just
+ * puts it back to sleep
+ */
+struct sem_inner {
+    cnt: i32,
+    wait_q: VecDeque<task_handle>,
+}
 
-// (pid, lifeid)
 pub struct kt_semaphore {
-    cnt: spin_mutex<i32, S_lock>,
-    wait_q: spin_mutex<Vec<(usize, usize)>, Critical_Area>,
+    inner: spin_mutex<sem_inner, Critical_Area>,
+}
+
+fn current_handle() -> task_handle {
+    let cpuid = which_cpu();
+    let (pid, lifeid) = get_ktpid_lifeid(cpuid).unwrap_or((INVAL_KTHREADS_PID, 0));
+    assert_ne!(pid, INVAL_KTHREADS_PID);
+    assert_ne!(lifeid, 0);
+
+    task_handle::new(pid, lifeid)
 }
 
 impl kt_semaphore {
     pub const fn new(new_cnt: i32) -> Self {
         assert!(new_cnt >= 0, "Semaphore must be non-negative!");
         Self {
-            cnt: spin_mutex::new(new_cnt),
-            wait_q: spin_mutex::new(Vec::new()),
+            inner: spin_mutex::new(sem_inner {
+                cnt: new_cnt,
+                wait_q: VecDeque::new(),
+            }),
         }
     }
 
-    pub fn wait(&mut self) {
-        let cpuid = which_cpu();
-        let (pid, lifeid) = get_ktpid_lifeid(cpuid).unwrap_or((INVAL_KTHREADS_PID, 0));
-        assert_ne!(pid, INVAL_KTHREADS_PID);
-        assert_ne!(lifeid, 0);
-
-        let mut cnt = self.cnt.lock();
-        *cnt -= 1;
-        if *cnt < 0 {
-            let mut wait_q = self.wait_q.lock();
-            wait_q.push((pid, lifeid));
-            drop(cnt);
-            drop(wait_q);
-            trapping(S2Mop::BLOCK, Some(&[pid, lifeid, 0, 0, 0]));
-        } else {
-            drop(cnt);
+    #[cfg_attr(feature = "lockdep", track_caller)]
+    fn is_waiting(&self, me: task_handle) -> bool {
+        self.inner.lock().wait_q.contains(&me)
+    }
+
+    pub fn wait(&self) {
+        let me = current_handle();
+
+        let mut inner = self.inner.lock();
+        inner.cnt -= 1;
+        if inner.cnt >= 0 {
+            return;
+        }
+        inner.wait_q.push_back(me);
+        drop(inner);
+
+        /*
+         * signal() may have woken us before BLOCK gets to M-mode, BLOCK comes back
+         * right away then
+         */
+        while self.is_waiting(me) {
+            trapping(S2Mop::BLOCK, Some(&[me.pid(), me.lifeid(), 0, 0, 0]));
         }
     }
 
-    pub fn signal(&mut self, hart: Option<usize>) {
+    /*
+     * wait() which gives up once mtime reaches deadline. Returns false on timeout, count
+     * is given back then
+     */
+    pub fn wait_until(&self, deadline: u64) -> bool {
+        let me = current_handle();
+
+        let mut inner = self.inner.lock();
+        inner.cnt -= 1;
+        if inner.cnt >= 0 {
+            return true;
+        }
+        inner.wait_q.push_back(me);
+        drop(inner);
+
+        loop {
+            sleep_until(deadline);
+
+            let mut inner = self.inner.lock();
+            match inner.wait_q.iter().position(|&waiter| waiter == me) {
+                None => return true,
+                Some(pos) => {
+                    if read_ticks() >= deadline {
+                        inner.wait_q.remove(pos);
+                        inner.cnt += 1;
+                        return false;
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn wait_timeout(&self, timeout: Duration) -> bool {
+        self.wait_until(read_ticks() + duration2ticks(timeout))
+    }
+
+    /*
+     * Waiter may sit on any hart, set_state_by_pid() kicks that hart when it's not the
+     * calling one
+     */
+    pub fn signal(&self, hart: Option<usize>) {
         let cpuid = hart.unwrap_or(INVAL_KTHREADS_PID);
         assert_ne!(cpuid, INVAL_KTHREADS_PID);
-        let mut cnt = self.cnt.lock();
-        *cnt += 1;
-
-        if *cnt <= 0 {
-            drop(cnt);
-
-            let mut wait_q = self.wait_q.lock();
-            if let Some((wake_pid, lifeid)) = wait_q.pop() {
-                let current_mode = get_cpu_mode(cpuid);
-                if matches!(current_mode, Mode::Machine | Mode::Machine_IRH) {
-                    unsafe {
-                        KTHREAD_POOL.set_state_by_pid(wake_pid, lifeid, task_state::Ready);
-                    }
-                } else {
-                    drop(wait_q);
-                    trapping(S2Mop::UNBLOCK, Some(&[wake_pid, lifeid, 0, 0, 0]));
+
+        let mut inner = self.inner.lock();
+        inner.cnt += 1;
+        if inner.cnt > 0 {
+            return;
+        }
+
+        let waiter = inner.wait_q.pop_front();
+        drop(inner);
+
+        if let Some(waiter) = waiter {
+            let current_mode = get_cpu_mode(cpuid);
+            if matches!(current_mode, Mode::Machine | Mode::Machine_IRH) {
+                unsafe {
+                    KTHREAD_POOL.wake_committed(waiter.pid(), waiter.lifeid());
                 }
+            } else {
+                trapping(
+                    S2Mop::UNBLOCK,
+                    Some(&[waiter.pid(), waiter.lifeid(), 0, 0, 0]),
+                );
             }
-        } else {
-            drop(cnt);
         }
     }
 }
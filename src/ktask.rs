@@ -4,10 +4,18 @@ use crate::cpu::{
     sscratch_write, which_cpu, Mode, SATP_mode, TrapFrame, MAX_HARTS,
 };
 use crate::ecall::{trapping, S2Mop};
+use crate::error::KError;
+use crate::ktask_manager::{kt_exit, kt_wait};
 use crate::kthread::get_ktpid_lifeid;
-use crate::kthread::INVAL_KTHREADS_PID;
+use crate::kthread::{task_flag, DEF_PRIO, EXIT_KILLED, INVAL_KTHREADS_PID};
+use crate::page::PAGE_SIZE;
+use crate::plic::extint_name;
 use crate::sem_uart;
+use crate::task::UTEST_ELF;
+use crate::vm::{mm, EntryBits};
+use crate::EXTINT_SRCS;
 use crate::IRQ_BUFFER;
+use crate::KTHREAD_POOL;
 use crate::{Mprintln, Sprintln};
 use crate::{M_UART, S_UART};
 use alloc::vec::Vec;
@@ -54,7 +62,7 @@ pub extern "C" fn ksem_test0() {
             trapping(S2Mop::YIELD, None);
         }
     }
-    trapping(S2Mop::EXIT, None);
+    kt_exit(0);
 }
 
 #[no_mangle]
@@ -73,8 +81,14 @@ pub extern "C" fn ktask_extint() {
                     let extint_id = new_req.get_extint_id();
                     let data = new_req.get_data();
 
-                    match extint_id {
-                        10 => {
+                    let src_name = EXTINT_SRCS
+                        .read()
+                        .get(extint_id as usize)
+                        .map(|src| *src.get_name())
+                        .unwrap_or(extint_name::UNDEF);
+
+                    match src_name {
+                        extint_name::UART0 => {
                             if let Some(ch) = data {
                                 let ch = *ch as u8;
                                 Sprintln!("Uart extint at CPU#{}: {}", hart, ch as char);
@@ -82,7 +96,7 @@ pub extern "C" fn ktask_extint() {
                                 Sprintln!("Uart extint at CPU#{}: Failed", hart);
                             }
                         }
-                        0 => {
+                        extint_name::UNDEF if extint_id == 0 => {
                             // do nothing
                         }
                         _ => {
@@ -99,16 +113,80 @@ pub extern "C" fn ktask_extint() {
             }
         }
     }
-    trapping(S2Mop::EXIT, None);
+    kt_exit(0);
 }
 
+/*
+ * Boot-time self-test of user mode: ELF loading, fork, IPC, user copy checks and killing
+ * a faulting task, then copy-on-write across mprotect. utest reports the step it failed
+ * at through its exit code
+ */
 #[no_mangle]
-pub extern "C" fn ktask_fallback() {
-    Sprintln!(
-        "CPU#{} at ktask fallbacker, trying to yield...",
-        which_cpu()
-    );
-    trapping(S2Mop::YIELD, None);
+pub extern "C" fn ktask_selftest() {
+    let cpuid = which_cpu();
+    let res = unsafe {
+        KTHREAD_POOL
+            .spawn_elf(UTEST_ELF, &["utest"], task_flag::NORMAL, DEF_PRIO, cpuid)
+            .and_then(kt_wait)
+    };
+
+    match res {
+        Ok(0) => Sprintln!("selftest: user mode passed"),
+        Ok(EXIT_KILLED) => Sprintln!("selftest: utest got killed"),
+        Ok(step) => Sprintln!("selftest: user mode FAILED at step {}", step),
+        Err(e) => Sprintln!("selftest: failed to run utest, {}", e),
+    }
+
+    match selftest_cow_protect() {
+        Ok(true) => Sprintln!("selftest: cow after mprotect passed"),
+        Ok(false) => Sprintln!("selftest: cow after mprotect FAILED, child wrote into parent"),
+        Err(e) => Sprintln!("selftest: failed to run cow after mprotect, {}", e),
+    }
+    kt_exit(0);
+}
+
+/*
+ * fork() leaves a read-only page shared without Cow. Granting write to the child later
+ * must still give it a private copy. Runs on two bare address spaces, true when parent
+ * keeps its own byte
+ */
+fn selftest_cow_protect() -> Result<bool, KError> {
+    let vaddr: usize = 0x30_0000_0000;
+    let ro_bits = EntryBits::Read.val() | EntryBits::User.val();
+    let mut parent = mm::new();
+    let mut child = mm::new();
+
+    let mut res = || -> Result<bool, KError> {
+        parent.init_user()?;
+        parent.map_range(vaddr, vaddr + PAGE_SIZE, ro_bits)?;
+        parent.load_user(vaddr, &[1])?;
+
+        child.fork_from(&mut parent)?;
+        child.protect(vaddr, vaddr + PAGE_SIZE, EntryBits::UserReadWrite.val())?;
+        child.copy_to_user(vaddr, &[2])?;
+
+        let mut seen = [0u8; 1];
+        parent.copy_from_user(vaddr, &mut seen)?;
+        Ok(seen[0] == 1)
+    };
+    let res = res();
+
+    child.release();
+    parent.release();
+    res
+}
+
+/*
+ * Per-hart idle task, runs only when hart has nothing Ready. Timer and msip still reach
+ * M-mode while hart sleeps here, preempt_handler() takes it out once there's work
+ */
+#[no_mangle]
+pub extern "C" fn ktask_idle() {
+    loop {
+        unsafe {
+            asm!("wfi");
+        }
+    }
 }
 
 #[no_mangle]
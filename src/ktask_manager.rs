@@ -1,15 +1,68 @@
+use crate::ecall::{trapping, S2Mop};
 use crate::error::{KError, KErrorType};
 use crate::ksemaphore::kt_semaphore;
-use crate::kthread::{task_flag, task_pool, task_state, task_struct};
+use crate::kthread::{task_flag, task_pool, task_state, task_struct, task_typ};
+use crate::kthread::{WAIT_DONE, WAIT_PENDING};
 use crate::new_kerror;
+use crate::pid::task_handle;
 use crate::KTHREAD_POOL;
+use core::ptr;
 
 impl task_pool {
-    pub fn spawn(&mut self, func: usize, new_flag: task_flag, cpuid: usize) -> Result<(), KError> {
+    pub fn spawn(
+        &mut self,
+        func: usize,
+        new_flag: task_flag,
+        prio: usize,
+        cpuid: usize,
+    ) -> Result<task_handle, KError> {
         let mut pcb_newtask = task_struct::new();
+        pcb_newtask.set_prio(prio)?;
         pcb_newtask.init(func, new_flag)?;
-        self.append_task(pcb_newtask, cpuid)?;
-        Ok(())
+        self.append_task(pcb_newtask, cpuid)
+    }
+
+    /*
+     * Same as spawn(), but the task never leaves cpuid. For tasks working on per-hart data
+     */
+    pub fn spawn_pinned(
+        &mut self,
+        func: usize,
+        new_flag: task_flag,
+        prio: usize,
+        cpuid: usize,
+    ) -> Result<task_handle, KError> {
+        let mut pcb_newtask = task_struct::new();
+        pcb_newtask.set_prio(prio)?;
+        pcb_newtask.set_affinity(1 << cpuid)?;
+        pcb_newtask.init(func, new_flag)?;
+        self.append_task(pcb_newtask, cpuid)
+    }
+
+    pub fn spawn_elf(
+        &mut self,
+        image: &[u8],
+        argv: &[&str],
+        new_flag: task_flag,
+        prio: usize,
+        cpuid: usize,
+    ) -> Result<task_handle, KError> {
+        let mut pcb_newtask = task_struct::new();
+        pcb_newtask.set_prio(prio)?;
+        pcb_newtask.init_elf(image, argv, new_flag)?;
+        self.append_task(pcb_newtask, cpuid)
+    }
+
+    /*
+     * Fork current task of cpuid, child lands on the same hart
+     */
+    pub fn fork(&mut self, cpuid: usize) -> Result<task_handle, KError> {
+        let mut pcb_child = task_struct::new();
+        let parent = self
+            .current_mut(cpuid)
+            .ok_or(new_kerror!(KErrorType::EINVAL))?;
+        pcb_child.fork_from(parent)?;
+        self.append_task(pcb_child, cpuid)
     }
 
     pub fn join_all_ktask(&mut self, cpuid: usize) -> Result<usize, KError> {
@@ -19,8 +72,52 @@ impl task_pool {
     }
 }
 
-impl task_struct {
-    pub fn exit(&mut self) {
-        self.set_state(task_state::Zombie);
+/*
+ * S-mode entry of task_pool::change_prio(), pid/lifeid may name any task on any hart
+ */
+pub fn kt_set_prio(pid: usize, lifeid: usize, prio: usize) -> Result<(), KError> {
+    match trapping(S2Mop::SETPRIO, Some(&[pid, lifeid, prio, 0, 0]))? {
+        0 => Ok(()),
+        _ => Err(new_kerror!(KErrorType::EINVAL)),
+    }
+}
+
+/*
+ * S-mode entry of task_pool::set_affinity()
+ */
+pub fn kt_set_affinity(pid: usize, lifeid: usize, mask: usize) -> Result<(), KError> {
+    match trapping(S2Mop::SETAFFINITY, Some(&[pid, lifeid, mask, 0, 0]))? {
+        0 => Ok(()),
+        _ => Err(new_kerror!(KErrorType::EINVAL)),
+    }
+}
+
+/*
+ * S-mode entry of task_pool::exit_current(), never comes back
+ */
+pub fn kt_exit(code: usize) {
+    trapping(S2Mop::EXIT, Some(&[code, 0, 0, 0, 0]));
+}
+
+/*
+ * Sleep until child exits and return its exit code. Only the task which
+ * spawned the child can wait on it, and code can be collected only once.
+ *
+ * Result words live on caller's stack, M-mode fills them either right away or when the
+ * child exits. Ret of SECALL_FRAME can't be used since task may come back on another hart
+ */
+pub fn kt_wait(child: task_handle) -> Result<usize, KError> {
+    let mut res: [usize; 2] = [WAIT_PENDING, 0];
+    loop {
+        trapping(
+            S2Mop::WAIT,
+            Some(&[child.pid(), child.lifeid(), res.as_mut_ptr() as usize, 0, 0]),
+        )?;
+
+        match unsafe { ptr::read_volatile(&res[0]) } {
+            WAIT_PENDING => continue,
+            WAIT_DONE => return Ok(unsafe { ptr::read_volatile(&res[1]) }),
+            _ => return Err(new_kerror!(KErrorType::EINVAL)),
+        }
     }
 }
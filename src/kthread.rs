@@ -1,34 +1,66 @@
 use crate::alloc::boxed::Box;
+use crate::alloc::collections::VecDeque;
 use crate::alloc::vec::Vec;
+use crate::asid::ASID_ALLOC;
 use crate::asm;
 use crate::cpu::{
     busy_delay, get_cpu_mode, make_satp, mepc_read, mepc_write, mscratch_write, satp_write,
-    sscratch_write, which_cpu, M_cli, M_sti, Mode, SATP_mode, TrapFrame, MAX_HARTS,
+    sscratch_write, which_cpu, M_cli, M_push_off, M_sti, Mode, SATP_mode, TrapFrame, MAX_HARTS,
 };
 use crate::ecall;
 use crate::ecall::S2Mop;
+use crate::elf::{load_elf, setup_user_stack};
 use crate::error::{KError, KErrorType};
+use crate::ipc::{ipc_state, set_syscall_ret, IPC_LOCK};
+use crate::ipi::{ipi_msg, send_ipi};
 use crate::kmem::{get_ksatp, get_page_table};
 use crate::ksemaphore::kt_semaphore;
-use crate::ktask::{ktask_extint, ktask_fallback};
-use crate::lock::{spin_mutex, S_lock};
+use crate::ktask::{ktask_extint, ktask_idle};
+use crate::ktimer::add_sleeper;
+use crate::lock::{spin_mutex, spin_mutex_guard, Critical_Area, M_lock, S_lock};
 use crate::new_kerror;
 use crate::page::PAGE_SIZE;
-use crate::vm::{ident_range_map, mem_map, range_unmap, EntryBits, PageEntry, PageTable};
+use crate::pid::{pid_allocator, task_handle};
+use crate::tlb::set_loaded_satp;
+use crate::vm::{ident_range_map, mm, range_unmap, EntryBits, PageEntry, PageTable};
 use crate::zone::{kfree_page, kmalloc_page, zone_type};
+use crate::CLINT;
 use crate::IRQ_BUFFER;
 use crate::KERNEL_TRAP_FRAME;
 use crate::KTHREAD_POOL;
 use crate::{Mprintln, Sprintln};
 use crate::{M_UART, S_UART};
-use cbitmap::bitmap::*;
 use core::cell::UnsafeCell;
 use core::hash::*;
+use core::ptr;
 use riscv::register::{mstatus, sstatus};
 
 pub const MAX_KTHREADS: usize = 256;
+
+/*
+ * Priority 0 is the highest one
+ */
+pub const NR_PRIO: usize = 8;
+pub const DEF_PRIO: usize = 4;
+
+/*
+ * Bit n of an affinity mask allows the task to run on hart n
+ */
+pub const ALL_HARTS: usize = (1 << MAX_HARTS) - 1;
 pub const INVAL_KTHREADS_PID: usize = MAX_KTHREADS + 10;
 
+/*
+ * Exit code of a task killed by kernel, e.g. on a bad page fault
+ */
+pub const EXIT_KILLED: usize = usize::MAX;
+
+/*
+ * First word of the result kt_wait() hands to S2Mop::WAIT, the second one gets exit code
+ */
+pub const WAIT_PENDING: usize = 0;
+pub const WAIT_DONE: usize = 1;
+pub const WAIT_ERR: usize = 2;
+
 #[derive(Clone, Copy)]
 pub enum task_state {
     Ready,
@@ -50,9 +82,15 @@ pub enum task_flag {
     NORMAL,
 }
 
-const MAX_KTASK: usize = 100;
-const KTASK_STACK_SZ: usize = 1 * PAGE_SIZE;
-const KTASK_EXPSTACK_SZ: usize = 1 * PAGE_SIZE;
+const KTASK_STACK_SZ: usize = PAGE_SIZE;
+const KTASK_EXPSTACK_SZ: usize = PAGE_SIZE;
+pub const UTASK_STACK_SZ: usize = 2 * PAGE_SIZE;
+
+/*
+ * User space lives far above kernel identity mappings so it never lands inside a top
+ * level entry shared from kernel root. src/user/uhello.lds links text at 0x20_0000_0000
+ */
+pub const UTASK_STACK_TOP: usize = 0x3f_ffff_f000;
 /*
  * cpu need to keep same as current hartid
  * We can have per-cpu schedule queue, and each task in a single queue need to have same cpu value
@@ -64,23 +102,69 @@ pub struct task_struct {
     stack_base: usize,
     exp_stack_base: usize,
     cpu: usize,
-    pid: usize,
+    id: task_handle,
     typ: task_typ,
     flag: task_flag,
-    life_id: usize,
+    mm: mm,
+    ipc: ipc_state,
+    prio: usize,
+    /*
+     * Whether pid of this task sits in a run queue, so it never gets queued twice
+     */
+    queued: bool,
+    affinity: usize,
+    exit_code: usize,
+    /*
+     * Task which created this one, None once parent is gone
+     */
+    parent: Option<task_handle>,
+    /*
+     * Child this task sleeps on, and for KERN task where its result words are
+     */
+    wait_for: Option<task_handle>,
+    wait_res: usize,
+    /*
+     * Deadline of the sleep task is in, 0 when not sleeping. A wakeup which doesn't match
+     * it is stale and ignored
+     */
+    wake_at: u64,
+    /*
+     * Woken through wake_committed() while still running, next Block doesn't happen
+     */
+    wake_pending: bool,
 }
 
+/*
+ * ZONE_NORMAL is identity mapped as a whole, so stacks are not unmapped from kernel page
+ * table here, otherwise next owner of those pages would fault in S-mode
+ */
 impl Drop for task_struct {
     fn drop(&mut self) {
-        let mut pageroot = unsafe { get_page_table().as_mut().unwrap() };
-
-        let kt_stack_begin: *mut u8 = (self.stack_base - KTASK_STACK_SZ) as *mut u8;
-        kfree_page(zone_type::ZONE_NORMAL, kt_stack_begin);
-        range_unmap(pageroot, kt_stack_begin as usize, self.stack_base);
+        /*
+         * init()/fork_from() may fail before exception stack exists
+         */
+        if self.exp_stack_base != 0 {
+            let exp_stack_begin: *mut u8 = (self.exp_stack_base - KTASK_EXPSTACK_SZ) as *mut u8;
+            kfree_page(
+                zone_type::ZONE_NORMAL,
+                exp_stack_begin,
+                KTASK_EXPSTACK_SZ / PAGE_SIZE,
+            );
+        }
 
-        let exp_stack_begin: *mut u8 = (self.exp_stack_base - KTASK_EXPSTACK_SZ) as *mut u8;
-        kfree_page(zone_type::ZONE_NORMAL, exp_stack_begin);
-        range_unmap(pageroot, exp_stack_begin as usize, self.exp_stack_base);
+        match self.typ {
+            task_typ::KERN => {
+                let kt_stack_begin: *mut u8 = (self.stack_base - KTASK_STACK_SZ) as *mut u8;
+                kfree_page(
+                    zone_type::ZONE_NORMAL,
+                    kt_stack_begin,
+                    KTASK_STACK_SZ / PAGE_SIZE,
+                );
+            }
+            task_typ::USER => {
+                self.mm.release();
+            }
+        }
     }
 }
 
@@ -89,14 +173,95 @@ impl task_struct {
         Self {
             trap_frame: TrapFrame::new(),
             state: task_state::Ready,
-            pc: 0 as usize,
-            cpu: 0 as usize,
-            stack_base: 0 as usize,
-            exp_stack_base: 0 as usize,
-            pid: 0 as usize,
+            pc: 0,
+            cpu: 0,
+            stack_base: 0,
+            exp_stack_base: 0,
+            id: task_handle::new(0, 0),
             typ: task_typ::KERN,
             flag: task_flag::NORMAL,
-            life_id: 0,
+            mm: mm::new(),
+            ipc: ipc_state::new(),
+            prio: DEF_PRIO,
+            queued: false,
+            affinity: ALL_HARTS,
+            exit_code: 0,
+            parent: None,
+            wait_for: None,
+            wait_res: 0,
+            wake_at: 0,
+            wake_pending: false,
+        }
+    }
+
+    pub fn get_typ(&self) -> task_typ {
+        self.typ
+    }
+
+    pub fn set_typ(&mut self, new_typ: task_typ) {
+        self.typ = new_typ;
+    }
+
+    pub fn get_prio(&self) -> usize {
+        self.prio
+    }
+
+    /*
+     * Only for tasks not handed to task_pool yet, use task_pool::change_prio() afterwards
+     */
+    pub fn set_prio(&mut self, new_prio: usize) -> Result<(), KError> {
+        if new_prio >= NR_PRIO {
+            return Err(new_kerror!(KErrorType::EINVAL));
+        }
+        self.prio = new_prio;
+        Ok(())
+    }
+
+    pub fn get_affinity(&self) -> usize {
+        self.affinity
+    }
+
+    /*
+     * Same as set_prio(), task_pool::set_affinity() is the one for tasks inside the pool
+     */
+    pub fn set_affinity(&mut self, new_mask: usize) -> Result<(), KError> {
+        if new_mask & ALL_HARTS == 0 {
+            return Err(new_kerror!(KErrorType::EINVAL));
+        }
+        self.affinity = new_mask & ALL_HARTS;
+        Ok(())
+    }
+
+    /*
+     * save()/resume_*() pick KERNEL_TRAP_FRAME by cpu, it must follow the task to whichever
+     * hart POOL it sits in
+     */
+    fn set_cpu(&mut self, new_cpu: usize) {
+        self.cpu = new_cpu;
+        self.trap_frame.cpuid = new_cpu;
+    }
+
+    pub fn get_exit_code(&self) -> usize {
+        self.exit_code
+    }
+
+    pub fn get_parent(&self) -> Option<task_handle> {
+        self.parent
+    }
+
+    /*
+     * Hand exit code of the child this task sleeps on over. U-mode gets it in a0, KERN task
+     * in the result words kt_wait() left on its stack
+     */
+    fn wait_done(&mut self, code: usize) {
+        self.wait_for = None;
+        match self.typ {
+            task_typ::USER => set_syscall_ret(&mut self.trap_frame, code),
+            task_typ::KERN => unsafe {
+                let res = self.wait_res as *mut usize;
+                res.add(1).write_volatile(code);
+                res.write_volatile(WAIT_DONE);
+            },
         }
     }
 
@@ -108,12 +273,32 @@ impl task_struct {
         self.state = new_state;
     }
 
-    pub fn get_lifeid(&self) -> usize {
-        self.life_id
+    pub fn get_pid(&self) -> usize {
+        self.id.pid()
+    }
+
+    pub fn get_handle(&self) -> task_handle {
+        self.id
+    }
+
+    pub fn get_mm(&mut self) -> &mut mm {
+        &mut self.mm
+    }
+
+    pub fn get_ipc(&self) -> &ipc_state {
+        &self.ipc
+    }
+
+    pub fn get_ipc_mut(&mut self) -> &mut ipc_state {
+        &mut self.ipc
     }
 
-    pub fn set_lifeid(&mut self, new_lifeid: usize) {
-        self.life_id = new_lifeid;
+    pub fn get_trap_frame_mut(&mut self) -> &mut TrapFrame {
+        &mut self.trap_frame
+    }
+
+    pub fn get_lifeid(&self) -> usize {
+        self.id.lifeid()
     }
     pub fn init(&mut self, func: usize, new_flag: task_flag) -> Result<usize, KError> {
         self.cpu = which_cpu();
@@ -124,7 +309,6 @@ impl task_struct {
             self.trap_frame.satp = get_ksatp() as usize;
             let mut pageroot = unsafe { get_page_table().as_mut().unwrap() };
             self.pc = func;
-            self.pid = 0;
             //initialize kernel task
             unsafe {
                 let kt_stack = kmalloc_page(zone_type::ZONE_NORMAL, KTASK_STACK_SZ / PAGE_SIZE)?
@@ -153,12 +337,79 @@ impl task_struct {
                 self.trap_frame.regs[2] = self.stack_base - 1;
             }
         } else {
-            //No user task here
-            return Err(new_kerror!(KErrorType::EFAULT));
+            /* User task only starts from an ELF image, see init_elf() */
+            return Err(new_kerror!(KErrorType::EINVAL));
+        }
+
+        Ok(0)
+    }
+
+    /*
+     * Same as init() for USER task, but text comes from an ELF image. argc/argv/auxv are
+     * left on user stack, a0/a1 also carry argc/argv for programs without crt0
+     */
+    pub fn init_elf(
+        &mut self,
+        image: &[u8],
+        argv: &[&str],
+        new_flag: task_flag,
+    ) -> Result<usize, KError> {
+        self.cpu = which_cpu();
+        self.trap_frame.cpuid = self.cpu;
+        self.state = task_state::Ready;
+        self.flag = new_flag;
+        self.typ = task_typ::USER;
+
+        self.mm.init_user()?;
+        let info = load_elf(&mut self.mm, image)?;
+        let sp = setup_user_stack(&mut self.mm, &info, UTASK_STACK_TOP, UTASK_STACK_SZ, argv)?;
+        self.pc = info.entry;
+
+        self.init_user_trap(sp)?;
+        self.trap_frame.regs[10] = argv.len();
+        self.trap_frame.regs[11] = sp + core::mem::size_of::<usize>();
+
+        Ok(0)
+    }
+
+    /*
+     * Child starts as an exact copy of parent's saved context, returning 0 from the same
+     * ecall. Caller has to save parent from KERNEL_TRAP_FRAME first
+     */
+    pub fn fork_from(&mut self, parent: &mut task_struct) -> Result<usize, KError> {
+        if !matches!(parent.typ, task_typ::USER) {
+            return Err(new_kerror!(KErrorType::EINVAL));
         }
 
+        self.cpu = parent.cpu;
+        self.state = task_state::Ready;
+        self.flag = parent.flag;
+        self.typ = task_typ::USER;
+        self.pc = parent.pc;
+        self.prio = parent.prio;
+        self.affinity = parent.affinity;
+
+        self.mm.fork_from(&mut parent.mm)?;
+        self.trap_frame = parent.trap_frame;
+        self.init_user_trap(parent.trap_frame.regs[2])?;
+        self.trap_frame.regs[10] = 0;
+
         Ok(0)
     }
+
+    fn init_user_trap(&mut self, sp: usize) -> Result<(), KError> {
+        unsafe {
+            let kt_expstack = kmalloc_page(zone_type::ZONE_NORMAL, KTASK_EXPSTACK_SZ / PAGE_SIZE)?
+                .add(KTASK_EXPSTACK_SZ);
+            self.exp_stack_base = kt_expstack as usize;
+        }
+
+        self.trap_frame.satp = self.mm.get_satp() as usize;
+        self.trap_frame.trap_stack = (self.exp_stack_base - 1) as *mut u8;
+        self.trap_frame.regs[2] = sp;
+
+        Ok(())
+    }
     /*
      * task.save will save records from KERNL_TRAP_FRAME to specific task's trapframe
      * infoe KERNEL_TRAP_FRAME.regs are guarantee to be the cpu state before trapping
@@ -179,19 +430,40 @@ impl task_struct {
         self.pc = newpc;
     }
 
+    /*
+     * Make sure the address space is running with an ASID of current generation before
+     * satp is loaded from trap_frame
+     */
+    fn switch_asid(&mut self) {
+        match self.typ {
+            task_typ::KERN => ASID_ALLOC.lock().deactivate(self.cpu),
+            task_typ::USER => self.trap_frame.satp = self.mm.switch_asid(self.cpu) as usize,
+        }
+    }
+
     pub fn resume_from_M(&self) {
         let next_pc = self.pc;
         unsafe {
             match self.typ {
                 task_typ::KERN => {
                     sstatus::set_spp(sstatus::SPP::Supervisor);
+                    mstatus::set_mpp(mstatus::MPP::Supervisor);
                 }
                 task_typ::USER => {
                     sstatus::set_spp(sstatus::SPP::User);
+                    mstatus::set_mpp(mstatus::MPP::User);
                 }
             }
             let tasktrap_addr = &self.trap_frame as *const TrapFrame;
             let task_s1val = KERNEL_TRAP_FRAME[self.cpu].regs[8];
+            set_loaded_satp(self.cpu, self.trap_frame.satp);
+
+            /*
+             * Translations of this ASID cached here earlier may be stale, shootdowns only
+             * reach harts which have the address space loaded. Everyone else keeps theirs
+             */
+            let task_asid = (self.trap_frame.satp >> 44) & 0xffff;
+            asm!("sfence.vma zero, {0}", in(reg) task_asid);
 
             sscratch_write(tasktrap_addr as usize);
 
@@ -242,12 +514,11 @@ impl task_struct {
             asm!("ld      x30, 30  * 8(s1)");
             asm!("ld      x31, 31  * 8(s1)");
             //load back satp value
-            asm!("ld      x9, 31 * 8(s1)");
+            asm!("ld      x9, 64 * 8(s1)");
             asm!("csrw      satp, s1");
 
             asm!("csrrw   s1, stval, s1");
 
-            asm!("sfence.vma");
             asm!("mret");
         }
     }
@@ -265,6 +536,14 @@ impl task_struct {
             }
             let tasktrap_addr = &self.trap_frame as *const TrapFrame;
             let task_s1val = KERNEL_TRAP_FRAME[self.cpu].regs[8];
+            set_loaded_satp(self.cpu, self.trap_frame.satp);
+
+            /*
+             * Translations of this ASID cached here earlier may be stale, shootdowns only
+             * reach harts which have the address space loaded. Everyone else keeps theirs
+             */
+            let task_asid = (self.trap_frame.satp >> 44) & 0xffff;
+            asm!("sfence.vma zero, {0}", in(reg) task_asid);
 
             sscratch_write(tasktrap_addr as usize);
 
@@ -314,24 +593,106 @@ impl task_struct {
 
             asm!("csrrw   s1, stval, s1");
 
-            asm!("sfence.vma");
-
             asm!("sret");
         }
     }
 }
 
+/*
+ * One FIFO of pids per priority level. Bit n of bitmap is set when level n is not empty,
+ * so finding the next task is a single trailing_zeros()
+ *
+ * Entries are not removed when a task blocks, pick_next() drops them lazily
+ */
+pub struct run_queue {
+    levels: [VecDeque<usize>; NR_PRIO],
+    bitmap: usize,
+}
+
+impl run_queue {
+    pub const fn new() -> Self {
+        run_queue {
+            levels: [const { VecDeque::new() }; NR_PRIO],
+            bitmap: 0,
+        }
+    }
+
+    fn push(&mut self, pid: usize, prio: usize) {
+        self.levels[prio].push_back(pid);
+        self.bitmap |= 1 << prio;
+    }
+
+    fn pop(&mut self) -> Option<usize> {
+        if self.bitmap == 0 {
+            return None;
+        }
+
+        let prio = self.bitmap.trailing_zeros() as usize;
+        let pid = self.levels[prio].pop_front();
+        if self.levels[prio].is_empty() {
+            self.bitmap &= !(1 << prio);
+        }
+
+        pid
+    }
+
+    fn remove(&mut self, pid: usize, prio: usize) {
+        if let Some(pos) = self.levels[prio].iter().position(|&queued| queued == pid) {
+            self.levels[prio].remove(pos);
+        }
+        if self.levels[prio].is_empty() {
+            self.bitmap &= !(1 << prio);
+        }
+    }
+}
+
+/*
+ * Serializes exit against wait, so a child can't turn Zombie between its parent finding
+ * it alive and parent going to sleep on it. Taken before any runq lock
+ */
+static EXIT_LOCK: spin_mutex<(), M_lock> = spin_mutex::new(());
+
+/*
+ * Guards POOL, runq and current_task of one hart. Owner hart takes it for its own
+ * bookkeeping, an idle hart takes victim's one as well while stealing, always in
+ * ascending hart order. Critical_Area since S-mode appends tasks as well
+ */
+static RUNQ_LOCK: [spin_mutex<(), Critical_Area>; MAX_HARTS] =
+    [const { spin_mutex::new(()) }; MAX_HARTS];
+
+type runq_guard = spin_mutex_guard<'static, (), Critical_Area>;
+
+/*
+ * Tasks are boxed so a task never moves in memory while it sits in POOL, sscratch of a
+ * running task keeps pointing at its trap_frame even if another hart swap_remove()s
+ * around it
+ */
 pub struct task_pool {
-    POOL: [Option<Box<Vec<task_struct>>>; MAX_HARTS],
+    #[allow(clippy::vec_box)]
+    POOL: [Option<Vec<Box<task_struct>>>; MAX_HARTS],
     onlline_cpu_cnt: usize,
     current_task: [Option<usize>; MAX_HARTS],
-    next_task: [Option<usize>; MAX_HARTS],
-    fallback_task: [Option<Box<task_struct>>; MAX_HARTS],
-    crit_task_intstate: [usize; MAX_HARTS],
-    pidmap: Option<spin_mutex<Bitmap<{ (MAX_KTASK / 8) + 1 }>, S_lock>>,
+    runq: [run_queue; MAX_HARTS],
+    /*
+     * Index of each pid inside its hart's POOL vector
+     */
+    pid_slot: [usize; MAX_KTHREADS],
+    idle_task: [Option<Box<task_struct>>; MAX_HARTS],
+    pids: Option<spin_mutex<pid_allocator, S_lock>>,
     pub sems: [Option<Vec<kt_semaphore>>; MAX_HARTS],
     is_init_sched: [bool; MAX_HARTS],
-    life_id: spin_mutex<usize, S_lock>,
+    /*
+     * true while hart runs a task from POOL, false while it runs fallback or has not
+     * entered scheduler yet
+     */
+    on_task: [bool; MAX_HARTS],
+    /*
+     * true while hart sits in its idle task, and mtime it went there. idle_ticks only
+     * counts finished idle periods
+     */
+    in_idle: [bool; MAX_HARTS],
+    idle_since: [u64; MAX_HARTS],
+    idle_ticks: [u64; MAX_HARTS],
 }
 
 impl task_pool {
@@ -340,98 +701,68 @@ impl task_pool {
             POOL: [None, None, None, None],
             onlline_cpu_cnt: MAX_HARTS,
             current_task: [None, None, None, None],
-            next_task: [None, None, None, None],
-            fallback_task: [None, None, None, None],
-            crit_task_intstate: [0; MAX_HARTS],
-            pidmap: None,
+            runq: [const { run_queue::new() }; MAX_HARTS],
+            pid_slot: [0; MAX_KTHREADS],
+            idle_task: [None, None, None, None],
+            pids: None,
             sems: [None, None, None, None],
-            life_id: spin_mutex::<usize, S_lock>::new(1),
             is_init_sched: [true, true, true, true],
+            on_task: [false; MAX_HARTS],
+            in_idle: [false; MAX_HARTS],
+            idle_since: [0; MAX_HARTS],
+            idle_ticks: [0; MAX_HARTS],
         }
     }
 
-    pub fn init(&mut self, cpucnt: usize) {
-        for cpuid in 0..cpucnt {
-            for (i, mut e) in self.POOL.iter_mut().enumerate() {
-                *e = Some(Box::new(Vec::new()));
-            }
+    /*
+     * Runs on BSP only, idle task of every hart is built here and moved to its hart
+     */
+    pub fn init(&mut self, cpucnt: usize) -> Result<(), KError> {
+        for cpuid in 0..cpucnt.min(MAX_HARTS) {
+            self.POOL[cpuid] = Some(Vec::new());
+
+            let mut idle = task_struct::new();
+            idle.init(ktask_idle as *const () as usize, task_flag::NORMAL)?;
+            idle.set_cpu(cpuid);
+            self.idle_task[cpuid] = Some(Box::new(idle));
 
-            for fallbacker in self.fallback_task.iter_mut() {
-                let mut fallb = task_struct::new();
-                fallb.init(ktask_fallback as usize, task_flag::NORMAL);
-                *fallbacker = Some(Box::new(fallb));
-            }
-            self.next_task[cpuid] = Some(0);
             self.current_task[cpuid] = Some(0);
-            self.pidmap = Some(spin_mutex::new(Bitmap::new()));
         }
-    }
+        self.pids = Some(spin_mutex::new(pid_allocator::new()));
 
-    fn get_new_pid(&mut self) -> usize {
-        let bind = self.pidmap.as_mut().unwrap();
-        let mut idmap = bind.lock();
-        let newid = idmap.find_first_zero();
-        match newid {
-            Some(new_id) => {
-                idmap.set(new_id);
-                return new_id;
-            }
-            None => {
-                panic!("Run out of task pid");
-            }
-        }
+        Ok(())
     }
 
-    fn reclaim_pid(&mut self, oldpid: usize) {
-        let bind = self.pidmap.as_mut().unwrap();
-        let mut idmap = bind.lock();
-        idmap.set(oldpid);
+    #[cfg_attr(feature = "lockdep", track_caller)]
+    fn lock_runq(cpuid: usize) -> runq_guard {
+        RUNQ_LOCK[cpuid].lock()
     }
-    fn generate_next(&mut self, cpuid: usize) -> Result<(), KError> {
-        let taskq = self.POOL[cpuid]
+
+    #[cfg_attr(feature = "lockdep", track_caller)]
+    fn get_new_pid(&mut self) -> Result<task_handle, KError> {
+        self.pids
             .as_ref()
-            .expect("Failed to take reference of task queue");
-        match self.next_task[cpuid] {
-            Some(ref mut next_ent) => 'state_check: loop {
-                let tmp = *next_ent;
-                let taskqlen = taskq.len();
-
-                if taskqlen == 0 {
-                    *next_ent = 0;
-                    break;
-                } else {
-                    *next_ent = (tmp + 1) % taskq.len();
-                    if let Some(ref taskvec) = self.POOL[cpuid] {
-                        let next_state = taskvec[*next_ent].get_state();
-                        match next_state {
-                            task_state::Ready => {
-                                break 'state_check;
-                            }
-                            task_state::Running => {
-                                break 'state_check;
-                            }
-                            task_state::Block => {
-                                break 'state_check;
-                            }
-                            _ => {
-                                Mprintln!("generate_next: unknown state");
-                                return Err(new_kerror!(KErrorType::EFAULT));
-                            }
-                        }
-                    } else {
-                        return Err(new_kerror!(KErrorType::EFAULT));
-                    }
-                }
-            },
-            None => {
-                return Err(new_kerror!(KErrorType::EFAULT));
-            }
-        }
+            .ok_or(new_kerror!(KErrorType::EINVAL))?
+            .lock()
+            .alloc()
+    }
 
-        Ok(())
+    #[cfg_attr(feature = "lockdep", track_caller)]
+    fn reclaim_pid(&mut self, oldpid: usize) {
+        if let Some(pids) = self.pids.as_ref() {
+            pids.lock().free(oldpid);
+        }
     }
 
+    /*
+     * KERNEL_TRAP_FRAME holds fallback's context when hart is not on a pool task, it must
+     * not end up in current task's trap frame
+     */
     pub fn save_from_ktrapframe(&mut self, cpuid: usize) -> Result<(), KError> {
+        if !self.on_task[cpuid] {
+            return Err(new_kerror!(KErrorType::EINVAL));
+        }
+
         if let (Some(cur_taskidx), Some(ref mut taskvec)) =
             (self.current_task[cpuid], &mut self.POOL[cpuid])
         {
@@ -482,6 +813,10 @@ impl task_pool {
     }
 
     pub fn set_currentPC(&mut self, cpuid: usize, newpc: usize) -> Result<(), KError> {
+        if !self.on_task[cpuid] {
+            return Err(new_kerror!(KErrorType::EINVAL));
+        }
+
         if let (Some(cur_taskidx), Some(ref mut taskvec)) =
             (self.current_task[cpuid], &mut self.POOL[cpuid])
         {
@@ -506,10 +841,6 @@ impl task_pool {
         // }
     }
 
-    pub fn get_crit_task_mie(&self) -> &[usize; MAX_HARTS] {
-        &self.crit_task_intstate
-    }
-
     pub fn get_current_fg(&self, cpuid: usize) -> Result<task_flag, KError> {
         if let (Some(cur_taskidx), Some(ref taskvec)) =
             (self.current_task[cpuid], &self.POOL[cpuid])
@@ -535,7 +866,7 @@ impl task_pool {
         if let (Some(cur_taskidx), Some(ref taskvec)) =
             (self.current_task[cpuid], &self.POOL[cpuid])
         {
-            Ok(taskvec[cur_taskidx].pid)
+            Ok(taskvec[cur_taskidx].get_pid())
         } else {
             Err(new_kerror!(KErrorType::EINVAL))
         }
@@ -556,7 +887,7 @@ impl task_pool {
         if let (Some(cur_taskidx), Some(ref taskvec)) =
             (self.current_task[cpuid], &self.POOL[cpuid])
         {
-            Ok(taskvec[cur_taskidx].life_id)
+            Ok(taskvec[cur_taskidx].get_lifeid())
         } else {
             Err(new_kerror!(KErrorType::EINVAL))
         }
@@ -573,149 +904,662 @@ impl task_pool {
         // }
     }
 
-    fn get_scheduable_cnt(&self, cpuid: usize) -> usize {
-        match self.POOL[cpuid] {
-            Some(ref taskvec) => {
-                let mut live_cnt = 0;
-                for task in taskvec.iter() {
-                    match task.get_state() {
-                        task_state::Ready => {
-                            live_cnt += 1;
-                        }
-                        task_state::Running => {
-                            live_cnt += 1;
-                        }
-                        _ => {
-                            live_cnt += 0;
-                        }
-                    }
-                }
-                return live_cnt;
+    pub fn is_on_task(&self, cpuid: usize) -> bool {
+        self.on_task[cpuid]
+    }
+
+    pub fn is_idle(&self, cpuid: usize) -> bool {
+        self.in_idle[cpuid]
+    }
+
+    /*
+     * mtime ticks cpuid has spent in its idle task so far
+     */
+    pub fn get_idle_ticks(&self, cpuid: usize) -> u64 {
+        let mut ticks = self.idle_ticks[cpuid];
+        if self.in_idle[cpuid] {
+            ticks += unsafe { CLINT.read_mtime() } - self.idle_since[cpuid];
+        }
+        ticks
+    }
+
+    /*
+     * Task running on calling hart becomes parent of new_task
+     */
+    pub fn append_task(
+        &mut self,
+        mut new_task: task_struct,
+        cpuid: usize,
+    ) -> Result<task_handle, KError> {
+        if cpuid >= MAX_HARTS || new_task.affinity & (1 << cpuid) == 0 {
+            return Err(new_kerror!(KErrorType::EINVAL));
+        }
+        new_task.set_cpu(cpuid);
+
+        let self_cpu = which_cpu();
+        if self.on_task[self_cpu] {
+            new_task.parent = self.current_id(self_cpu).ok();
+        }
+
+        new_task.id = self.get_new_pid()?;
+        let new_id = new_task.id;
+        let new_pid = new_id.pid();
+
+        let _guard = Self::lock_runq(cpuid);
+        if let Some(taskvec) = &mut self.POOL[cpuid] {
+            self.pid_slot[new_pid] = taskvec.len();
+            taskvec.push(Box::new(new_task));
+        } else {
+            self.reclaim_pid(new_pid);
+            return Err(new_kerror!(KErrorType::EINVAL));
+        }
+
+        self.enqueue(cpuid, new_pid);
+
+        Ok(new_id)
+    }
+
+    fn enqueue(&mut self, cpuid: usize, pid: usize) {
+        let idx = self.pid_slot[pid];
+        if let Some(task) = self.POOL[cpuid].as_mut().and_then(|v| v.get_mut(idx)) {
+            if !task.queued {
+                task.queued = true;
+                self.runq[cpuid].push(pid, task.prio);
             }
-            None => {
-                return 0;
+        }
+    }
+
+    /*
+     * Highest priority runnable task of cpuid. Stale entries, of blocked tasks or of ones
+     * no longer in this hart's pool, are dropped on the way
+     */
+    fn pick_next(&mut self, cpuid: usize) -> Option<usize> {
+        while let Some(pid) = self.runq[cpuid].pop() {
+            let idx = self.pid_slot[pid];
+            let task = match self.POOL[cpuid].as_mut().and_then(|pool| pool.get_mut(idx)) {
+                Some(task) if task.id.pid() == pid => task,
+                _ => continue,
+            };
+            task.queued = false;
+            if matches!(task.state, task_state::Ready | task_state::Running) {
+                return Some(idx);
+            }
+        }
+
+        None
+    }
+
+    /*
+     * Caller holds runq lock of cpuid
+     */
+    fn find_on(&self, cpuid: usize, target_pid: usize, target_lifeid: usize) -> Option<usize> {
+        let idx = *self.pid_slot.get(target_pid)?;
+        let task = self.POOL[cpuid].as_ref()?.get(idx)?;
+        if task.id == task_handle::new(target_pid, target_lifeid) {
+            Some(idx)
+        } else {
+            None
+        }
+    }
+
+    /*
+     * Each POOL vector is only looked at under its own runq lock, another hart may push to
+     * it or swap_remove() from it meanwhile. Locks are taken one by one in ascending hart
+     * order, the answer may be stale by the time it comes back
+     */
+    #[cfg_attr(feature = "lockdep", track_caller)]
+    fn locate(&self, target_pid: usize, target_lifeid: usize) -> Option<(usize, usize)> {
+        for cpuid in 0..MAX_HARTS {
+            let _guard = Self::lock_runq(cpuid);
+            if let Some(idx) = self.find_on(cpuid, target_pid, target_lifeid) {
+                return Some((cpuid, idx));
             }
         }
+
+        None
     }
 
-    pub fn append_task(&mut self, mut new_task: task_struct, cpuid: usize) -> Result<(), KError> {
-        new_task.pid = self.get_new_pid();
+    /*
+     * locate() with the owner hart locked. Task may be stolen between lookup and lock,
+     * so lookup is repeated until both agree
+     */
+    #[cfg_attr(feature = "lockdep", track_caller)]
+    fn locate_locked(
+        &self,
+        target_pid: usize,
+        target_lifeid: usize,
+    ) -> Option<(usize, usize, runq_guard)> {
+        loop {
+            let (cpuid, _) = self.locate(target_pid, target_lifeid)?;
+            let guard = Self::lock_runq(cpuid);
+            if let Some(idx) = self.find_on(cpuid, target_pid, target_lifeid) {
+                return Some((cpuid, idx, guard));
+            }
+        }
+    }
 
-        let mut new_lifeid = self.life_id.lock();
+    /*
+     * Caller holds runq lock of cpuid. Once current_task is set here the task is Running
+     * and no other hart will steal it
+     */
+    fn set_current(&mut self, cpuid: usize, idx: usize) {
+        if self.in_idle[cpuid] {
+            self.in_idle[cpuid] = false;
+            self.idle_ticks[cpuid] += unsafe { CLINT.read_mtime() } - self.idle_since[cpuid];
+        }
+        self.current_task[cpuid] = Some(idx);
+        self.on_task[cpuid] = true;
+        if let Some(task) = self.current_mut(cpuid) {
+            task.state = task_state::Running;
+        }
+    }
 
-        new_task.life_id = *new_lifeid;
-        *new_lifeid += 1;
-        drop(new_lifeid);
+    /*
+     * Pull the highest priority queued task which is allowed on cpuid from another hart
+     * and make it current task of cpuid
+     */
+    fn steal(&mut self, cpuid: usize) -> bool {
+        for off in 1..MAX_HARTS {
+            let victim = (cpuid + off) % MAX_HARTS;
+            if self.POOL[victim].is_none() || self.runq[victim].bitmap == 0 {
+                continue;
+            }
 
-        if let Some(boxvec) = &mut self.POOL[cpuid] {
-            boxvec.push(new_task);
-        } else {
+            let (_low_guard, _high_guard) = if victim < cpuid {
+                (Self::lock_runq(victim), Self::lock_runq(cpuid))
+            } else {
+                (Self::lock_runq(cpuid), Self::lock_runq(victim))
+            };
+
+            if let Some(idx) = self.steal_from(victim, cpuid) {
+                self.set_current(cpuid, idx);
+                return true;
+            }
+        }
+
+        false
+    }
+
+    fn steal_from(&mut self, victim: usize, cpuid: usize) -> Option<usize> {
+        let victim_vec = self.POOL[victim].as_ref()?;
+        let mut found: Option<(usize, usize)> = None;
+        'level: for prio in 0..NR_PRIO {
+            for &pid in self.runq[victim].levels[prio].iter() {
+                let idx = self.pid_slot[pid];
+                let task = &victim_vec[idx];
+                let is_current = self.on_task[victim] && self.current_task[victim] == Some(idx);
+                if matches!(task.state, task_state::Ready)
+                    && task.affinity & (1 << cpuid) != 0
+                    && !is_current
+                {
+                    found = Some((pid, idx));
+                    break 'level;
+                }
+            }
+        }
+
+        let (pid, idx) = found?;
+        let mut task = self.take_task(victim, idx)?;
+        task.queued = false;
+        task.set_cpu(cpuid);
+        let own_vec = self.POOL[cpuid].as_mut()?;
+        let new_idx = own_vec.len();
+        self.pid_slot[pid] = new_idx;
+        own_vec.push(task);
+
+        Some(new_idx)
+    }
+
+    /*
+     * New mask has to keep the hart task currently sits on, affinity never moves a task by
+     * itself, it only limits where the task can be stolen to
+     */
+    pub fn set_affinity(
+        &mut self,
+        target_pid: usize,
+        target_lifeid: usize,
+        new_mask: usize,
+    ) -> Result<(), KError> {
+        let (cpuid, idx, _guard) = self
+            .locate_locked(target_pid, target_lifeid)
+            .ok_or(new_kerror!(KErrorType::EFAULT))?;
+        if new_mask & (1 << cpuid) == 0 {
             return Err(new_kerror!(KErrorType::EINVAL));
         }
 
+        self.POOL[cpuid].as_mut().unwrap()[idx].set_affinity(new_mask)
+    }
+
+    /*
+     * Move a task to another priority level, a queued task is requeued at the tail of
+     * its new level
+     */
+    pub fn change_prio(
+        &mut self,
+        target_pid: usize,
+        target_lifeid: usize,
+        new_prio: usize,
+    ) -> Result<(), KError> {
+        if new_prio >= NR_PRIO {
+            return Err(new_kerror!(KErrorType::EINVAL));
+        }
+
+        let (cpuid, idx, _guard) = self
+            .locate_locked(target_pid, target_lifeid)
+            .ok_or(new_kerror!(KErrorType::EFAULT))?;
+        let task = &mut self.POOL[cpuid].as_mut().unwrap()[idx];
+        let old_prio = task.prio;
+        task.prio = new_prio;
+
+        if task.queued {
+            self.runq[cpuid].remove(target_pid, old_prio);
+            self.runq[cpuid].push(target_pid, new_prio);
+        }
+
         Ok(())
     }
 
-    pub fn remove_cur_task(&mut self, cpuid: usize) -> Result<(), KError> {
-        let mut died_pid: usize = 0;
-        if let Some(cur_taskidx) = &mut self.current_task[cpuid] {
-            match &mut self.POOL[cpuid] {
-                Some(ref mut boxvec) => {
-                    died_pid = boxvec[*cur_taskidx].pid;
-                    boxvec.swap_remove(*cur_taskidx);
+    /*
+     * Caller holds runq lock of cpuid. swap_remove() moves last task of cpuid into idx,
+     * its pid_slot and current_task of cpuid follow it
+     */
+    fn take_task(&mut self, cpuid: usize, idx: usize) -> Option<Box<task_struct>> {
+        let taskvec = self.POOL[cpuid].as_mut()?;
+        if idx >= taskvec.len() {
+            return None;
+        }
+
+        let last = taskvec.len() - 1;
+        let task = taskvec.swap_remove(idx);
+        if task.queued {
+            self.runq[cpuid].remove(task.get_pid(), task.prio);
+        }
+        if let Some(moved) = taskvec.get(idx) {
+            self.pid_slot[moved.get_pid()] = idx;
+        }
+
+        if self.current_task[cpuid] == Some(idx) {
+            self.current_task[cpuid] = None;
+        } else if self.current_task[cpuid] == Some(last) {
+            self.current_task[cpuid] = Some(idx);
+        }
+
+        Some(task)
+    }
+
+    /*
+     * Take a Zombie out of pool for good. Box is handed back so caller can drop it once
+     * its own locks are released, freeing an address space may wait on other harts
+     */
+    fn unlink(&mut self, target_pid: usize, target_lifeid: usize) -> Option<Box<task_struct>> {
+        let (cpuid, idx, guard) = self.locate_locked(target_pid, target_lifeid)?;
+        if !matches!(self.POOL[cpuid].as_ref()?[idx].state, task_state::Zombie) {
+            return None;
+        }
+
+        let task = self.take_task(cpuid, idx);
+        drop(guard);
+        self.reclaim_pid(target_pid);
+
+        task
+    }
+
+    /*
+     * Current task of cpuid becomes a Zombie holding code and never runs again. A parent
+     * sleeping on it collects code right away, a task nobody can wait on is reaped right
+     * away, otherwise it stays until parent calls wait_child(). Tasks blocked in IPC with
+     * it get IPC_ERR. Caller should sched() next
+     */
+    pub fn exit_current(&mut self, cpuid: usize, code: usize) -> Result<(), KError> {
+        let me = self.current_id(cpuid)?;
+        let exit_guard = EXIT_LOCK.lock();
+        let ipc_guard = IPC_LOCK.lock();
+
+        let guard = Self::lock_runq(cpuid);
+        let cur_task = self
+            .current_mut(cpuid)
+            .ok_or(new_kerror!(KErrorType::EINVAL))?;
+        cur_task.state = task_state::Zombie;
+        cur_task.exit_code = code;
+        let parent = cur_task.parent;
+        let (queued, prio) = (cur_task.queued, cur_task.prio);
+        cur_task.queued = false;
+        if queued {
+            self.runq[cpuid].remove(me.pid(), prio);
+        }
+        self.current_task[cpuid] = None;
+        drop(guard);
+
+        self.ipc_peer_gone(me);
+        drop(ipc_guard);
+
+        /*
+         * Children live on without a parent, the ones already dead have nobody left to
+         * collect them
+         */
+        let mut orphans: Vec<task_handle> = Vec::new();
+        self.for_each_task(|task| {
+            if task.parent == Some(me) {
+                task.parent = None;
+                if matches!(task.state, task_state::Zombie) {
+                    orphans.push(task.id);
                 }
-                None => {
-                    return Err(new_kerror!(KErrorType::EFAULT));
+            }
+        });
+
+        let mut dead: Vec<Box<task_struct>> = Vec::new();
+        for orphan in orphans {
+            dead.extend(self.unlink(orphan.pid(), orphan.lifeid()));
+        }
+
+        match parent.and_then(|parent| self.task_by_pid(parent.pid(), parent.lifeid())) {
+            Some(parent_task) => {
+                if parent_task.wait_for == Some(me) {
+                    parent_task.wait_done(code);
+                    let parent = parent.unwrap();
+                    dead.extend(self.unlink(me.pid(), me.lifeid()));
+                    self.set_state_by_pid(parent.pid(), parent.lifeid(), task_state::Ready)?;
                 }
             }
-            self.reclaim_pid(died_pid);
+            None => {
+                dead.extend(self.unlink(me.pid(), me.lifeid()));
+            }
         }
 
+        drop(exit_guard);
+        drop(dead);
+
         Ok(())
     }
 
+    /*
+     * Collect exit code of child target of current task. Ok(None) means current task is
+     * now blocked, exit_current() of child delivers the code through wait_done(). res_addr
+     * is only used by KERN task
+     */
+    pub fn wait_child(
+        &mut self,
+        cpuid: usize,
+        target: task_handle,
+        res_addr: usize,
+    ) -> Result<Option<usize>, KError> {
+        let me = self.current_id(cpuid)?;
+        let exit_guard = EXIT_LOCK.lock();
+
+        let child = self
+            .task_by_pid(target.pid(), target.lifeid())
+            .ok_or(new_kerror!(KErrorType::EFAULT))?;
+        if child.parent != Some(me) {
+            return Err(new_kerror!(KErrorType::EINVAL));
+        }
+
+        if matches!(child.state, task_state::Zombie) {
+            let code = child.exit_code;
+            let dead = self.unlink(target.pid(), target.lifeid());
+            drop(exit_guard);
+            drop(dead);
+            return Ok(Some(code));
+        }
+
+        let cur_task = self
+            .current_mut(cpuid)
+            .ok_or(new_kerror!(KErrorType::EINVAL))?;
+        cur_task.wait_for = Some(target);
+        cur_task.wait_res = res_addr;
+        cur_task.state = task_state::Block;
+
+        Ok(None)
+    }
+
+    /*
+     * Nothing is Ready on cpuid, park it in its idle task. Idle task always starts over
+     * from its entry, it keeps nothing worth saving
+     */
     pub fn fallback(&mut self, cpuid: usize) -> Result<(), KError> {
-        Mprintln!("Entered ksched fallbacker");
-        match self.fallback_task[cpuid] {
-            Some(ref mut fallbacker) => {
+        self.on_task[cpuid] = false;
+        if !self.in_idle[cpuid] {
+            self.in_idle[cpuid] = true;
+            self.idle_since[cpuid] = unsafe { CLINT.read_mtime() };
+        }
+
+        match self.idle_task[cpuid] {
+            Some(ref mut idler) => {
+                idler.switch_asid();
                 let current_mode = get_cpu_mode(cpuid);
                 if matches!(current_mode, Mode::Machine | Mode::Machine_IRH) {
-                    fallbacker.resume_from_M();
+                    idler.resume_from_M();
                     Ok(())
                 } else {
-                    fallbacker.resume_from_S();
+                    idler.resume_from_S();
                     Ok(())
                 }
             }
-            None => {
-                return Err(new_kerror!(KErrorType::EINVAL));
-            }
+            None => Err(new_kerror!(KErrorType::EINVAL)),
         }
     }
 
+    /*
+     * Current task goes back to the tail of its level if it is still runnable, then the
+     * head of the highest non-empty level runs, or a task stolen from another hart when
+     * local queue is empty. Returns only when there's nothing to run, caller should
+     * fallback() then
+     */
     pub fn sched(&mut self, cpuid: usize) -> Result<(), KError> {
-        let live_cnt = self.get_scheduable_cnt(cpuid);
-        self.generate_next(cpuid)?;
-        self.current_task[cpuid] = self.next_task[cpuid];
-
-        if let Some(cur_taskidx) = self.current_task[cpuid] {
-            match self.POOL[cpuid] {
-                //TODO
-                //live_cnt will becomes 0 when all tasks are "blocked"
-                //in this case we should not clear the whole taskvec
-                //this is a bug to fix for line 655
-                Some(ref mut taskvec) => {
-                    if live_cnt == 0 {
-                        // taskvec.clear();
-                        // self.current_task = [Some(0), Some(0), Some(0), Some(0)];
-                        // self.next_task = [Some(0), Some(0), Some(0), Some(0)];
-                        return Ok(());
-                    }
-
-                    let current_mode = get_cpu_mode(cpuid);
-                    if matches!(current_mode, Mode::Machine | Mode::Machine_IRH) {
-                        if let task_flag::CRITICAL = taskvec[cur_taskidx].flag {
-                            let prev_xie = M_cli();
-                            self.crit_task_intstate[cpuid] = prev_xie;
-                        }
-
-                        taskvec[cur_taskidx].resume_from_M();
-                        Ok(())
-                    } else {
-                        taskvec[cur_taskidx].resume_from_S();
-                        Ok(())
-                    }
+        let guard = Self::lock_runq(cpuid);
+        if self.on_task[cpuid] {
+            if let Some(cur_task) = self.current_mut(cpuid) {
+                if matches!(cur_task.state, task_state::Ready | task_state::Running) {
+                    cur_task.state = task_state::Ready;
+                    let cur_pid = cur_task.get_pid();
+                    self.enqueue(cpuid, cur_pid);
                 }
-                None => {
-                    return Err(new_kerror!(KErrorType::EINVAL));
+            }
+        }
+
+        match self.pick_next(cpuid) {
+            Some(next_idx) => {
+                self.set_current(cpuid, next_idx);
+                drop(guard);
+            }
+            None => {
+                /*
+                 * Nothing left locally, try to pull some work from a busier hart
+                 */
+                drop(guard);
+                if !self.steal(cpuid) {
+                    return Ok(());
                 }
             }
+        }
+
+        /*
+         * Index of current task may still change under a stealing hart, the boxed task
+         * itself doesn't move
+         */
+        let guard = Self::lock_runq(cpuid);
+        let next_task = self
+            .current_mut(cpuid)
+            .ok_or(new_kerror!(KErrorType::EINVAL))? as *mut task_struct;
+        drop(guard);
+        let next_task = unsafe { &mut *next_task };
+        next_task.switch_asid();
+
+        let current_mode = get_cpu_mode(cpuid);
+        if matches!(current_mode, Mode::Machine | Mode::Machine_IRH) {
+            if let task_flag::CRITICAL = next_task.flag {
+                M_push_off();
+            }
+
+            next_task.resume_from_M();
         } else {
-            return Err(new_kerror!(KErrorType::EINVAL));
+            if let task_flag::CRITICAL = next_task.flag {
+                ecall::trapping(S2Mop::CLI, None);
+            }
+
+            next_task.resume_from_S();
         }
+
+        Ok(())
+    }
+
+    pub fn current_id(&self, cpuid: usize) -> Result<task_handle, KError> {
+        Ok(task_handle::new(
+            self.get_current_pid(cpuid)?,
+            self.get_current_lifeid(cpuid)?,
+        ))
+    }
+
+    pub fn current_mut(&mut self, cpuid: usize) -> Option<&mut task_struct> {
+        let cur_taskidx = self.current_task[cpuid]?;
+        self.POOL[cpuid]
+            .as_mut()?
+            .get_mut(cur_taskidx)
+            .map(|task| task.as_mut())
     }
 
     /*
-     * Low efficiency, but correct
+     * Boxed task stays where it is after runq lock is gone, even if it gets stolen. Only
+     * reaping frees it, which EXIT_LOCK holders and the task's own hart are safe from.
+     * So are IPC_LOCK holders as long as the task they found is not a Zombie
      */
-    pub fn set_state_by_pid(
+    #[cfg_attr(feature = "lockdep", track_caller)]
+    pub fn task_by_pid(
         &mut self,
         target_pid: usize,
         target_lifeid: usize,
-        new_state: task_state,
-    ) -> Result<(), KError> {
+    ) -> Option<&mut task_struct> {
+        let (cpuid, idx, _guard) = self.locate_locked(target_pid, target_lifeid)?;
+        self.POOL[cpuid]
+            .as_mut()?
+            .get_mut(idx)
+            .map(|task| unsafe { &mut *(task.as_mut() as *mut task_struct) })
+    }
+
+    /*
+     * f runs with runq lock of the task's hart held, it must not touch the run queues. A
+     * task stolen meanwhile may be seen twice or not at all
+     */
+    #[cfg_attr(feature = "lockdep", track_caller)]
+    pub fn for_each_task<F: FnMut(&mut task_struct)>(&mut self, mut f: F) {
         for cpuid in 0..MAX_HARTS {
-            if let Some(ref mut taskvec) = self.POOL[cpuid] {
+            let _guard = Self::lock_runq(cpuid);
+            if let Some(taskvec) = self.POOL[cpuid].as_mut() {
                 for task in taskvec.iter_mut() {
-                    if task.pid == target_pid {
-                        assert!(task.life_id == target_lifeid);
-                        task.set_state(new_state);
-                        return Ok(());
-                    }
+                    f(task);
                 }
             }
         }
-        Err(new_kerror!(KErrorType::EFAULT))
+    }
+
+    /*
+     * Put current task of cpuid to sleep until mtime reaches deadline. False when
+     * deadline has already passed, task keeps running then
+     */
+    pub fn sleep_current(&mut self, cpuid: usize, deadline: u64) -> Result<bool, KError> {
+        let me = self.current_id(cpuid)?;
+        if !add_sleeper(cpuid, deadline, me) {
+            return Ok(false);
+        }
+
+        let cur_task = self
+            .current_mut(cpuid)
+            .ok_or(new_kerror!(KErrorType::EINVAL))?;
+        if cur_task.wake_pending {
+            cur_task.wake_pending = false;
+            return Ok(false);
+        }
+        cur_task.wake_at = deadline;
+        cur_task.state = task_state::Block;
+
+        Ok(true)
+    }
+
+    /*
+     * Timer side of sleep_current(), target may be gone or woken by someone else already
+     */
+    pub fn wake_sleeper(&mut self, target: task_handle, deadline: u64) {
+        let (cpuid, idx, guard) = match self.locate_locked(target.pid(), target.lifeid()) {
+            Some(found) => found,
+            None => return,
+        };
+        if self.POOL[cpuid].as_ref().unwrap()[idx].wake_at != deadline {
+            return;
+        }
+        drop(guard);
+
+        self.set_state_by_pid(target.pid(), target.lifeid(), task_state::Ready);
+    }
+
+    /*
+     * Wake a task which committed to sleep, e.g. a waiter signal() took off a semaphore
+     * wait queue. It may still be running on its way to BLOCK or sleep_current(), its
+     * next Block is skipped then instead of the wakeup getting lost
+     */
+    pub fn wake_committed(
+        &mut self,
+        target_pid: usize,
+        target_lifeid: usize,
+    ) -> Result<(), KError> {
+        let (cpuid, idx, guard) = self
+            .locate_locked(target_pid, target_lifeid)
+            .ok_or(new_kerror!(KErrorType::EFAULT))?;
+        let task = &mut self.POOL[cpuid].as_mut().unwrap()[idx];
+        if matches!(task.state, task_state::Running | task_state::Ready) {
+            task.wake_pending = true;
+            return Ok(());
+        }
+        drop(guard);
+
+        self.set_state_by_pid(target_pid, target_lifeid, task_state::Ready)
+    }
+
+    /*
+     * Every task that becomes Ready has to come through here, so it gets back into
+     * run queue of its hart. A task which is Running already stays as it is, one that
+     * may be on its way to sleep has to be woken through wake_committed()
+     */
+    pub fn set_state_by_pid(
+        &mut self,
+        target_pid: usize,
+        target_lifeid: usize,
+        new_state: task_state,
+    ) -> Result<(), KError> {
+        let (cpuid, idx, guard) = self
+            .locate_locked(target_pid, target_lifeid)
+            .ok_or(new_kerror!(KErrorType::EFAULT))?;
+        let task = &mut self.POOL[cpuid].as_mut().unwrap()[idx];
+        if matches!(task.state, task_state::Zombie) {
+            return Err(new_kerror!(KErrorType::EINVAL));
+        }
+
+        match new_state {
+            task_state::Ready if matches!(task.state, task_state::Running) => {
+                return Ok(());
+            }
+            task_state::Block if task.wake_pending => {
+                task.wake_pending = false;
+                return Ok(());
+            }
+            _ => {}
+        }
+        task.set_state(new_state);
+
+        if let task_state::Ready = new_state {
+            task.wake_at = 0;
+            self.enqueue(cpuid, target_pid);
+            drop(guard);
+
+            /*
+             * Owner hart may sit in another task or in wfi for a whole quantum otherwise.
+             * Calling hart kicks itself when idle, msip fires once it's back in idle task.
+             * A full mailbox only delays the wakeup until next timer tick
+             */
+            if cpuid != which_cpu() || self.in_idle[cpuid] {
+                let _ = send_ipi(cpuid, ipi_msg::RESCHED);
+            }
+        }
+
+        Ok(())
     }
 }
 
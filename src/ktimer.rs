@@ -0,0 +1,120 @@
+use crate::alloc::collections::BinaryHeap;
+use crate::cpu::MAX_HARTS;
+use crate::ecall::{trapping, S2Mop};
+use crate::lock::{spin_mutex, M_lock};
+use crate::pid::task_handle;
+use crate::CLINT;
+use crate::KTHREAD_POOL;
+use core::cmp::Reverse;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+
+/*
+ * mtime frequency, 10MHz on qemu virt
+ */
+pub const TIMEBASE_HZ: u64 = 10_000_000;
+
+/*
+ * mtime at which current time slice of each hart ends
+ */
+static SLICE_END: [AtomicU64; MAX_HARTS] = [const { AtomicU64::new(0) }; MAX_HARTS];
+
+/*
+ * Pending wakeups of tasks sleeping on each hart, earliest deadline on top. Only touched
+ * from M-mode
+ */
+type sleep_queue = BinaryHeap<Reverse<(u64, task_handle)>>;
+
+static SLEEPERS: [spin_mutex<sleep_queue, M_lock>; MAX_HARTS] =
+    [const { spin_mutex::new(BinaryHeap::new()) }; MAX_HARTS];
+
+pub fn read_ticks() -> u64 {
+    unsafe { CLINT.read_mtime() }
+}
+
+pub fn duration2ticks(duration: Duration) -> u64 {
+    (duration.as_nanos() * TIMEBASE_HZ as u128 / 1_000_000_000) as u64
+}
+
+/*
+ * mtimecmp holds whichever comes first, end of time slice or nearest wakeup
+ */
+fn program_timer(hart: usize) {
+    let mut next = SLICE_END[hart].load(Ordering::Relaxed);
+    if let Some(Reverse((deadline, _))) = SLEEPERS[hart].lock().peek() {
+        next = next.min(*deadline);
+    }
+
+    unsafe {
+        CLINT.set_mtimecmp(hart, next);
+    }
+}
+
+/*
+ * Start a new time slice on hart
+ */
+pub fn arm_slice(hart: usize) {
+    let quantum = unsafe { CLINT.get_quantum() };
+    SLICE_END[hart].store(read_ticks() + quantum, Ordering::Relaxed);
+    program_timer(hart);
+}
+
+/*
+ * Wake task who at deadline. False when deadline has already passed
+ */
+pub fn add_sleeper(hart: usize, deadline: u64, who: task_handle) -> bool {
+    if deadline <= read_ticks() {
+        return false;
+    }
+
+    SLEEPERS[hart].lock().push(Reverse((deadline, who)));
+    program_timer(hart);
+
+    true
+}
+
+/*
+ * Machine timer interrupt. Every due sleeper is woken, returns true when time slice of
+ * hart is used up as well
+ */
+pub fn timer_tick(hart: usize) -> bool {
+    let now = read_ticks();
+    loop {
+        let mut sleepers = SLEEPERS[hart].lock();
+        let (deadline, who) = match sleepers.peek() {
+            Some(Reverse((deadline, who))) if *deadline <= now => (*deadline, *who),
+            _ => break,
+        };
+        sleepers.pop();
+        drop(sleepers);
+
+        unsafe {
+            KTHREAD_POOL.wake_sleeper(who, deadline);
+        }
+    }
+
+    let expired = now >= SLICE_END[hart].load(Ordering::Relaxed);
+    if expired {
+        arm_slice(hart);
+    } else {
+        program_timer(hart);
+    }
+
+    expired
+}
+
+/*
+ * Block calling kernel thread until mtime reaches deadline. May come back earlier when
+ * somebody makes the task Ready on purpose, e.g. kt_semaphore::signal()
+ */
+pub fn sleep_until(deadline: u64) {
+    if deadline <= read_ticks() {
+        return;
+    }
+
+    trapping(S2Mop::SLEEP, Some(&[deadline as usize, 0, 0, 0, 0]));
+}
+
+pub fn sleep_for(duration: Duration) {
+    sleep_until(read_ticks() + duration2ticks(duration));
+}
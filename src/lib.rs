@@ -4,6 +4,9 @@
 #![allow(non_camel_case_types)]
 #![allow(non_snake_case)]
 #![allow(static_mut_refs)]
+#![allow(clippy::new_without_default)]
+#![allow(clippy::upper_case_acronyms)]
+#![allow(clippy::empty_loop)]
 #![feature(variant_count)]
 
 extern crate alloc;
@@ -42,22 +45,23 @@ use kmem::init;
 use riscv::register::{medeleg, mideleg, mie, mstatus, sie, sstatus};
 use spin::Mutex;
 
-use crate::lock::spin_mutex;
+use crate::lock::{spin_mutex, spin_rwlock};
 use crate::lock::{Critical_Area, M_lock, S_lock};
 use alloc::vec::Vec;
 use clint::clint_controller;
 use cpu::{get_cpu_mode, which_cpu, SATP_mode, TrapFrame};
+use devtree::platform_info;
 use ecall::{ecall_args, S2Mop};
 use error::{KError, KErrorType};
-use fdt_parser::Fdt;
 use irq::{int_request, soft_irq_buf};
 use ksemaphore::kt_semaphore;
-use ktask::{ksem_test0, ktask_extint, KHello_task0, KHello_task1};
-use kthread::{task_flag, task_pool, task_struct};
+use ktask::{ksem_test0, ktask_extint, ktask_selftest, KHello_task0, KHello_task1};
+use kthread::{task_flag, task_pool, task_struct, DEF_PRIO};
 use nobsp_kfunc::kinit as nobsp_kinit;
 use nobsp_kfunc::kmain as nobsp_kmain;
 use plic::{extint_name, extint_src, plic_controller, plic_ctx};
 use ringbuffer::AllocRingBuffer;
+use task::UHELLO_ELF;
 use vm::{ident_range_map, virt2phys};
 use zone::{kfree_page, kmalloc_page, zone_type};
 
@@ -166,11 +170,12 @@ pub extern "C" fn eh_func_nobsp_kmain() {
 // | |  _| |  | | | |  _ \ / _ \ | |      \ \ / / _ \ | |_) \___ \
 // | |_| | |__| |_| | |_) / ___ \| |___    \ V / ___ \|  _ < ___) |
 //  \____|_____\___/|____/_/   \_\_____|    \_/_/   \_\_| \_\____/
-pub const ZONE_DEFVAL: spin_mutex<zone::mem_zone, S_lock> =
-    spin_mutex::<zone::mem_zone, S_lock>::new(zone::mem_zone::new());
-
-pub static SYS_ZONES: [spin_mutex<zone::mem_zone, S_lock>; 3] =
-    [ZONE_DEFVAL; zone_type::type_cnt()];
+/*
+ * Page faults and copy-on-write break take pages from M-mode trap handlers, so a holder
+ * keeps machine interrupts off its hart as well
+ */
+pub static SYS_ZONES: [spin_rwlock<zone::mem_zone, Critical_Area>; 3] =
+    [const { spin_rwlock::new(zone::mem_zone::new()) }; zone_type::type_cnt()];
 
 pub static M_UART: spin_mutex<uart::Uart, M_lock> =
     spin_mutex::<uart::Uart, M_lock>::new(uart::Uart::new(0x1000_0000));
@@ -183,12 +188,14 @@ pub static mut PLIC: plic_controller = plic_controller::new(plic::PLIC_BASE);
 pub static mut CLINT: clint_controller = clint_controller::new(clint::CLINT_BASE);
 pub static mut SECALL_FRAME: [ecall_args; cpu::MAX_HARTS] = [ecall_args::new(); cpu::MAX_HARTS];
 
-pub static mut cust_hmalloc: spin_mutex<allocator::custom_kheap_malloc, S_lock> = spin_mutex::<
-    allocator::custom_kheap_malloc,
-    S_lock,
->::new(
-    allocator::custom_kheap_malloc::new(),
-);
+/*
+ * M-mode interrupt handlers allocate too, e.g. a run queue push when UART ISR wakes a
+ * task, so heap is a Critical_Area like SYS_ZONES
+ */
+pub static mut cust_hmalloc: spin_mutex<allocator::custom_kheap_malloc, Critical_Area> =
+    spin_mutex::<allocator::custom_kheap_malloc, Critical_Area>::new(
+        allocator::custom_kheap_malloc::new(),
+    );
 
 pub static mut IRQ_BUFFER: soft_irq_buf = soft_irq_buf::new();
 
@@ -197,32 +204,78 @@ pub static mut sem_uart: kt_semaphore = kt_semaphore::new(0);
 #[global_allocator]
 pub static glob_alloc: allocator::kheap_alloc = allocator::kheap_alloc::new();
 
-pub static mut EXTINT_SRCS: [extint_src; plic::MAX_INTCNT] = [extint_src::new(); plic::MAX_INTCNT];
+/*
+ * Filled once in kinit(), only looked up afterwards
+ */
+pub static EXTINT_SRCS: spin_rwlock<[extint_src; plic::MAX_INTCNT], Critical_Area> =
+    spin_rwlock::new([extint_src::new(); plic::MAX_INTCNT]);
 
 pub static mut KTHREAD_POOL: task_pool = task_pool::new();
 
+pub static mut PLATFORM: platform_info = platform_info::new();
+
 fn kinit() -> Result<usize, KError> {
+    /*
+     * Device tree goes first, UART base comes from it. Nothing can be printed until
+     * UART is initialized, so parse error is reported afterwards
+     */
+    let fdt_result = unsafe { PLATFORM.parse(fdt_base) };
+
+    unsafe {
+        M_UART.lock().set_base(PLATFORM.get_uart().get_base());
+        S_UART.lock().set_base(PLATFORM.get_uart().get_base());
+        PLIC.set_base(PLATFORM.get_plic().get_base());
+        CLINT.set_base(PLATFORM.get_clint().get_base());
+    }
+
     M_UART.lock().init();
     Mprintln!("\nHello world");
 
     let current_cpu = cpu::mhartid_read();
     Mprintln!("Initializer running on CPU#{}", current_cpu);
 
+    if let Err(er_code) = fdt_result {
+        Mprintln!("{}", er_code);
+        Mprintln!("Failed to parse device tree, using default platform layout");
+    }
+
+    unsafe {
+        PLATFORM.print_info();
+    }
+
     /*
      * Setting up new zone
      */
-    let (meta_begin, meta_end) = SYS_ZONES[zone_type::ZONE_NORMAL.val()].lock().init(
-        ptr::addr_of!(_heap_start),
-        unsafe {
-            ptr::addr_of!(_heap_end).sub(0x100_0000) //prevent enter fdt area
-        },
+    let heap_start = ptr::addr_of!(_heap_start);
+    let kern_region = unsafe { PLATFORM.kernel_region(heap_start as usize) }
+        .ok_or(new_kerror!(KErrorType::ENOMEM))?;
+
+    let (meta_begin, meta_end) = SYS_ZONES[zone_type::ZONE_NORMAL.val()].write().init(
+        heap_start,
+        kern_region.end() as *const u8,
         zone_type::ZONE_NORMAL,
-        zone::AllocatorSelector::NaiveAllocator,
+        zone::AllocatorSelector::BuddyAllocator,
     )?;
 
-    SYS_ZONES[zone_type::ZONE_UNDEF.val()].lock().init(
-        0 as *const u8,
-        0 as *const u8,
+    /*
+     * FDT blob and reserved regions sit inside RAM, take them out before kmem::init()
+     * makes the first allocation
+     */
+    unsafe {
+        let mut normal_zone = SYS_ZONES[zone_type::ZONE_NORMAL.val()].write();
+        let fdt_region = PLATFORM
+            .fdt_region(heap_start as usize)
+            .ok_or(new_kerror!(KErrorType::ENOMEM))?;
+        normal_zone.reserve_pages(fdt_region.get_base(), fdt_region.end())?;
+
+        for region in PLATFORM.rsv_regions() {
+            normal_zone.reserve_pages(region.get_base(), region.end())?;
+        }
+    }
+
+    SYS_ZONES[zone_type::ZONE_UNDEF.val()].write().init(
+        ptr::null(),
+        ptr::null(),
         zone_type::ZONE_UNDEF,
         zone::AllocatorSelector::EmptyAllocator,
     )?;
@@ -258,12 +311,19 @@ fn kinit() -> Result<usize, KError> {
         vm::EntryBits::ReadExecute.val(),
     );
 
-    // let usz_heap_start = ptr::addr_of!(_heap_start) as usize;
-    // let usz_heap_end = usz_heap_start + SYS_ZONES[zone_type::ZONE_NORMAL.val()].lock().get_size()?;
-    // ident_range_map(pageroot,
-    //         usz_heap_start,
-    //         usz_heap_end,
-    //         vm::EntryBits::ReadWrite.val());
+    /*
+     * Whole ZONE_NORMAL is identity mapped, S-mode code needs to fill page tables and user
+     * pages which it never mapped by itself
+     */
+    let usz_heap_start = ptr::addr_of!(_heap_start) as usize;
+    let usz_heap_end =
+        usz_heap_start + SYS_ZONES[zone_type::ZONE_NORMAL.val()].read().get_size()?;
+    ident_range_map(
+        pageroot,
+        usz_heap_start,
+        usz_heap_end,
+        vm::EntryBits::ReadWrite.val(),
+    );
 
     ident_range_map(
         pageroot,
@@ -307,108 +367,69 @@ fn kinit() -> Result<usize, KError> {
         vm::EntryBits::ReadWrite.val(),
     );
 
-    //uart mmio area
-    ident_range_map(
-        pageroot,
-        aligl_4k!(ptr::addr_of!(_virtio_start) as usize),
-        aligh_4k!(ptr::addr_of!(_virtio_end) as usize),
-        vm::EntryBits::ReadWrite.val(),
-    );
-
-    //qemu mmio memory mapping according to qemu/hw/riscv/virt.c
-
-    //CLINT
-    ident_range_map(
-        pageroot,
-        0x0200_0000,
-        0x0200_ffff,
-        vm::EntryBits::ReadWrite.val(),
-    );
-
-    //PLIC
+    //mmio memory mapping according to device tree
     unsafe {
+        //uart mmio area
+        let uart = PLATFORM.get_uart();
+        ident_range_map(
+            pageroot,
+            uart.get_base(),
+            uart.get_base() + uart.get_size(),
+            vm::EntryBits::ReadWrite.val(),
+        );
+
+        //CLINT
+        let clint = PLATFORM.get_clint();
         ident_range_map(
             pageroot,
-            PLIC.base,
-            PLIC.base + (plic_ctx::max_ctx() + 2) * 0x1000,
+            clint.get_base(),
+            clint.get_base() + clint.get_size(),
             vm::EntryBits::ReadWrite.val(),
         );
 
+        //PLIC
+        let plic = PLATFORM.get_plic();
         ident_range_map(
             pageroot,
-            PLIC.thres_base,
-            PLIC.thres_base + plic_ctx::max_ctx() * 0x1000,
+            plic.get_base(),
+            plic.get_base() + plic.get_size(),
             vm::EntryBits::ReadWrite.val(),
         );
+
+        for dev in PLATFORM.virtio_devs() {
+            ident_range_map(
+                pageroot,
+                dev.get_base(),
+                dev.get_base() + dev.get_size(),
+                vm::EntryBits::ReadWrite.val(),
+            );
+        }
     }
 
-    let paddr = 0x1000_0000 as usize;
-    let vaddr = virt2phys(&pageroot, paddr)?.unwrap_or(0);
+    let paddr = unsafe { PLATFORM.get_uart().get_base() };
+    let vaddr = virt2phys(pageroot, paddr)?.unwrap_or(0);
 
     Mprintln!("VM Walker test: Paddr: {:#x} -> Vaddr: {:#x}", paddr, vaddr);
 
-    // unsafe {
-    //     let mut fdt_addr = ptr::NonNull::new(fdt_base as *mut u8).unwrap();
-
-    //     match Fdt::from_ptr(fdt_addr) {
-    //         Ok(fdt_table) => {
-    //             Mprintln!("FDT version: {}", fdt_table.version());
-    //             for region in fdt_table.memory_reservation_block() {
-    //                 Mprintln!("region: {:?}", region);
-    //             }
-
-    //             for node in fdt_table.all_nodes() {
-    //                 let space = " ".repeat((node.level - 1) * 4);
-    //                 Mprintln!("{}{}", space, node.name());
-
-    //                 Mprintln!("{} -compatible: ", space);
-    //                 for cap in node.compatibles() {
-    //                     Mprintln!("{}     {:?}", space, cap);
-    //                 }
-
-    //                 if let Some(reg) = node.reg() {
-    //                     Mprintln!("{} - reg: ", space);
-    //                     for cell in reg {
-    //                         Mprintln!("{}     {:?}", space, cell);
-    //                     }
-    //                 }
-
-    //                 for prop in node.propertys() {
-    //                     Mprintln!(
-    //                         "{}   Property: {} = {:?}",
-    //                         space,
-    //                         prop.name,
-    //                         prop.raw_value()
-    //                     );
-    //                 }
-    //             }
-    //         }
-    //         Err(errmsg) => {
-    //             panic!("fdt parser failed: {:?}", errmsg);
-    //         }
-    //     }
-    // }
     /*
      * Memory allocation for trap stack
      */
     unsafe {
-        for cpu_cnt in 0..cpu::MAX_HARTS {
-            KERNEL_TRAP_FRAME[cpu_cnt].cpuid = cpu_cnt;
+        let hart_cnt = PLATFORM.get_hart_cnt();
+        for (cpu_cnt, frame) in KERNEL_TRAP_FRAME.iter_mut().enumerate().take(hart_cnt) {
+            frame.cpuid = cpu_cnt;
 
-            KERNEL_TRAP_FRAME[cpu_cnt].trap_stack =
-                kmalloc_page(zone_type::ZONE_NORMAL, 2)?.add(page::PAGE_SIZE * 2);
+            frame.trap_stack = kmalloc_page(zone_type::ZONE_NORMAL, 2)?.add(page::PAGE_SIZE * 2);
 
             ident_range_map(
                 pageroot,
-                KERNEL_TRAP_FRAME[cpu_cnt]
-                    .trap_stack
-                    .sub(2 * page::PAGE_SIZE) as usize,
-                KERNEL_TRAP_FRAME[cpu_cnt].trap_stack as usize,
+                frame.trap_stack.sub(2 * page::PAGE_SIZE) as usize,
+                frame.trap_stack as usize,
                 vm::EntryBits::ReadWrite.val(),
             );
 
-            let trapstack_paddr = KERNEL_TRAP_FRAME[cpu_cnt].trap_stack as usize - 1;
-            let trapstack_vaddr = virt2phys(&pageroot, trapstack_paddr)?.unwrap_or(0);
+            let trapstack_paddr = frame.trap_stack as usize - 1;
+            let trapstack_vaddr = virt2phys(pageroot, trapstack_paddr)?.unwrap_or(0);
 
             Mprintln!(
                 "CPU#{} TrapStack: (vaddr){:#x} -> (paddr){:#x}",
@@ -417,8 +438,8 @@ fn kinit() -> Result<usize, KError> {
                 trapstack_vaddr
             );
 
-            let trapfram_paddr = ptr::addr_of_mut!(KERNEL_TRAP_FRAME[cpu_cnt]) as usize;
-            let trapfram_vaddr = virt2phys(&pageroot, trapfram_paddr)?.unwrap_or(0);
+            let trapfram_paddr = frame as *mut TrapFrame as usize;
+            let trapfram_vaddr = virt2phys(pageroot, trapfram_paddr)?.unwrap_or(0);
 
             Mprintln!(
                 "CPU#{} TrapFrame: (vaddr){:#x} -> (paddr){:#x}",
@@ -439,8 +460,9 @@ fn kinit() -> Result<usize, KError> {
         CLINT.set_mtimecmp(current_cpu, u64::MAX);
 
         mie::set_msoft();
+        ipi::set_online(current_cpu);
 
-        // mie::set_mtimer();
+        mie::set_mtimer();
 
         mie::set_mext();
         mie::set_sext();
@@ -449,38 +471,60 @@ fn kinit() -> Result<usize, KError> {
         sie::set_sext();
         sstatus::set_spie();
 
-        EXTINT_SRCS[10].set_name(extint_name::UART0);
-        EXTINT_SRCS[10].set_src_id(10);
-        PLIC.set_prio(&EXTINT_SRCS[10], 5)?;
-        PLIC.enable(plic_ctx::CORE0_M, &EXTINT_SRCS[10])?;
-        PLIC.enable(plic_ctx::CORE1_M, &EXTINT_SRCS[10])?;
-        PLIC.enable(plic_ctx::CORE2_M, &EXTINT_SRCS[10])?;
-        PLIC.enable(plic_ctx::CORE3_M, &EXTINT_SRCS[10])?;
+        let uart_irq = PLATFORM.get_uart().get_irq();
+        if uart_irq == 0 || uart_irq > PLATFORM.get_plic_ndev() {
+            return Err(new_kerror!(KErrorType::EINVAL));
+        }
+
+        let mut extint_srcs = EXTINT_SRCS.write();
+        extint_srcs[uart_irq].set_name(extint_name::UART0);
+        extint_srcs[uart_irq].set_src_id(uart_irq);
+        PLIC.set_prio(&extint_srcs[uart_irq], 5)?;
+        for hart in 0..PLATFORM.get_hart_cnt() {
+            PLIC.enable(plic::id2plic_mctx(hart), &extint_srcs[uart_irq])?;
+        }
+
+        /*
+         * virtio sources are only registered, nobody handles them yet so they stay
+         * disabled in PLIC
+         */
+        for dev in PLATFORM.virtio_devs() {
+            let virtio_irq = dev.get_irq();
+            if virtio_irq != 0 && virtio_irq <= PLATFORM.get_plic_ndev() {
+                extint_srcs[virtio_irq].set_name(extint_name::VIRTIO);
+                extint_srcs[virtio_irq].set_src_id(virtio_irq);
+            }
+        }
+        drop(extint_srcs);
+
         mstatus::set_mpp(mstatus::MPP::Supervisor);
     }
 
     unsafe {
-        KTHREAD_POOL.init(cpu::MAX_HARTS);
+        KTHREAD_POOL.init(PLATFORM.get_hart_cnt())?;
     }
     /*
-     * Unlock other cores from early spin lock
+     * Unlock other cores from early spin lock, harts beyond MAX_HARTS have no per-hart
+     * state and stay there
      */
     unsafe {
         let early_boot: *mut u64 = ptr::addr_of_mut!(cpu_early_block);
-        early_boot.write_volatile(0xffff_ffff);
+        early_boot.write_volatile((1 << PLATFORM.get_hart_cnt()) - 1);
     }
 
+    asid::ASID_ALLOC.lock().init(asid::probe_asid_bits());
+
     /*
      * Set up satp register to provide paging mode and PPN of
      * root page table
      */
-    cpu::satp_write(SATP_mode::Sv39, 0, pageroot_ptr as usize);
+    cpu::satp_write(SATP_mode::Sv39, asid::ASID_KERN, pageroot_ptr as usize);
 
     kmem::set_ksatp(cpu::satp_read());
     /*
      * Set up arrival address of S-mode entry
      */
-    cpu::mepc_write(eh_func_kmain as usize);
+    cpu::mepc_write(eh_func_kmain as *const () as usize);
 
     cpu::flush_tlb();
 
@@ -495,7 +539,7 @@ fn kmain(current_cpu: usize) -> Result<(), KError> {
         asm!("ebreak");
 
         Sprintln!("CPU{} Back from trap\n", current_cpu);
-        CLINT.set_mtimecmp(current_cpu, CLINT.read_mtime() + 0x500_000);
+        ktimer::arm_slice(current_cpu);
     }
 
     let k = alloc::vec![1, 2, 3, 4, 5];
@@ -513,16 +557,30 @@ fn kmain(current_cpu: usize) -> Result<(), KError> {
         // let mut pcb_second: task_struct = task_struct::new();
         let sched_cpu = which_cpu();
 
-        // KTHREAD_POOL.spawn(KHello_task0 as usize, task_flag::NORMAL, sched_cpu)?;
-        // KTHREAD_POOL.spawn(KHello_task1 as usize, task_flag::NORMAL, sched_cpu)?;
-        // KTHREAD_POOL.spawn(ksem_test0 as usize, task_flag::NORMAL, sched_cpu)?;
-        KTHREAD_POOL.spawn(ktask_extint as usize, task_flag::CRITICAL, sched_cpu)?;
+        // KTHREAD_POOL.spawn(KHello_task0 as usize, task_flag::NORMAL, DEF_PRIO, sched_cpu)?;
+        // KTHREAD_POOL.spawn(KHello_task1 as usize, task_flag::NORMAL, DEF_PRIO, sched_cpu)?;
+        // KTHREAD_POOL.spawn(ksem_test0 as usize, task_flag::NORMAL, DEF_PRIO, sched_cpu)?;
+        // KTHREAD_POOL.spawn_elf(UHELLO_ELF, &["uhello"], task_flag::NORMAL, DEF_PRIO, sched_cpu)?;
+        KTHREAD_POOL.spawn(
+            ktask_selftest as *const () as usize,
+            task_flag::NORMAL,
+            DEF_PRIO,
+            sched_cpu,
+        )?;
+        KTHREAD_POOL.spawn_pinned(
+            ktask_extint as *const () as usize,
+            task_flag::CRITICAL,
+            DEF_PRIO,
+            sched_cpu,
+        )?;
         KTHREAD_POOL.join_all_ktask(sched_cpu);
     }
 
     loop {
         //Not suppose to reach here
-        cpu::busy_delay(1);
+        unsafe {
+            asm!("wfi");
+        }
     }
 
     Ok(())
@@ -530,22 +588,31 @@ fn kmain(current_cpu: usize) -> Result<(), KError> {
 
 #[macro_use]
 pub mod allocator;
+pub mod asid;
 pub mod clint;
 pub mod cpu;
+pub mod devtree;
 pub mod ecall;
+pub mod elf;
 pub mod error;
+pub mod ipc;
+pub mod ipi;
 pub mod irq;
 pub mod kmem;
+pub mod kmutex;
 pub mod ksemaphore;
 pub mod ktask;
 pub mod ktask_manager;
 pub mod kthread;
+pub mod ktimer;
 pub mod lock;
 pub mod macros;
 pub mod nobsp_kfunc;
 pub mod page;
+pub mod pid;
 pub mod plic;
 pub mod task;
+pub mod tlb;
 pub mod trap;
 pub mod uart;
 pub mod vm;
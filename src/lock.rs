@@ -1,9 +1,11 @@
-use crate::cpu::{get_cpu_mode, which_cpu, M_cli, M_sti, Mode, S_cli, S_sti};
+use crate::cpu::{get_cpu_mode, which_cpu, M_pop_off, M_push_off, Mode, S_pop_off, S_push_off};
 use crate::ecall::{trapping, S2Mop};
 use crate::SECALL_FRAME;
 use crate::{M_UART, S_UART};
 use core::arch::asm;
 use core::ops::{Deref, DerefMut};
+#[cfg(feature = "lockdep")]
+use core::panic::Location;
 use spin::{Mutex, RwLock};
 
 pub trait IntControl {
@@ -15,49 +17,60 @@ pub struct M_lock;
 pub struct S_lock;
 pub struct Critical_Area;
 
+/*
+ * cli() returns whatever sti() needs to undo it, nesting itself is kept by per-hart
+ * push_off()/pop_off() counters, so only the outermost guard turns interrupts back on
+ */
 impl IntControl for M_lock {
+    /*
+     * Data behind M_lock is touched by M-mode trap handlers, so holder must keep them off
+     * its own hart in either mode. S-mode has no access to mie and asks M-mode through
+     * ecall, same as Critical_Area
+     */
     fn cli() -> usize {
-        // M_cli()
-        0
+        Critical_Area::cli()
     }
 
     fn sti(prev_xie: usize) {
-        // M_sti(prev_xie)
+        Critical_Area::sti(prev_xie)
     }
 }
 
 impl IntControl for S_lock {
     fn cli() -> usize {
-        S_cli()
+        S_push_off();
+        0
     }
 
-    fn sti(prev_xie: usize) {
-        S_sti(prev_xie)
+    fn sti(_: usize) {
+        S_pop_off();
     }
 }
 
+/*
+ * Masks machine interrupts from either mode, S-mode asks M-mode to do it through ecall
+ */
 impl IntControl for Critical_Area {
     fn cli() -> usize {
         let cpuid = which_cpu();
-
-        // assert_eq!(cpuid, 0);
-
         let current_mode = get_cpu_mode(cpuid);
 
-        if !(current_mode == Mode::Machine || current_mode == Mode::Machine_IRH) {
+        if current_mode == Mode::Machine || current_mode == Mode::Machine_IRH {
+            M_push_off();
+        } else {
             trapping(S2Mop::CLI, None);
         }
 
         0
     }
 
-    fn sti(prev_xie: usize) {
+    fn sti(_: usize) {
         let cpuid = which_cpu();
-        // assert_eq!(cpuid, 0);
-
         let current_mode = get_cpu_mode(cpuid);
 
-        if !(current_mode == Mode::Machine || current_mode == Mode::Machine_IRH) {
+        if current_mode == Mode::Machine || current_mode == Mode::Machine_IRH {
+            M_pop_off();
+        } else {
             trapping(S2Mop::STI, None);
         }
     }
@@ -76,12 +89,18 @@ impl<T, MODE: IntControl> spin_mutex<T, MODE> {
         }
     }
 
+    #[cfg_attr(feature = "lockdep", track_caller)]
     pub fn lock(&self) -> spin_mutex_guard<'_, T, MODE> {
         let prev_xie = MODE::cli();
 
+        #[cfg(feature = "lockdep")]
+        lockdep::acquire(self as *const _ as usize, true, Location::caller());
+
         spin_mutex_guard::<T, MODE> {
             dat: self.inner_lock.lock(),
             old_xie: prev_xie,
+            #[cfg(feature = "lockdep")]
+            class: self as *const _ as usize,
             _mode: core::marker::PhantomData,
         }
     }
@@ -90,11 +109,16 @@ impl<T, MODE: IntControl> spin_mutex<T, MODE> {
 pub struct spin_mutex_guard<'a, T, MODE: IntControl> {
     pub dat: spin::MutexGuard<'a, T>,
     old_xie: usize,
+    #[cfg(feature = "lockdep")]
+    class: usize,
     _mode: core::marker::PhantomData<MODE>,
 }
 
 impl<T, MODE: IntControl> Drop for spin_mutex_guard<'_, T, MODE> {
     fn drop(&mut self) {
+        #[cfg(feature = "lockdep")]
+        lockdep::release(self.class);
+
         MODE::sti(self.old_xie);
     }
 }
@@ -113,76 +137,356 @@ impl<'a, T, MODE: IntControl> DerefMut for spin_mutex_guard<'a, T, MODE> {
     }
 }
 
-// pub struct irq_rwlock<T>{
-//     inner_lock: RwLock<T>
-// }
+/*
+ * Many readers or one writer, interrupts are handled by MODE the same way as spin_mutex.
+ * Meant for data which is set up once and looked up all the time afterwards
+ */
+pub struct spin_rwlock<T, MODE: IntControl> {
+    inner_lock: RwLock<T>,
+    _mode: core::marker::PhantomData<MODE>,
+}
+
+impl<T, MODE: IntControl> spin_rwlock<T, MODE> {
+    pub const fn new(dat: T) -> Self {
+        Self {
+            inner_lock: RwLock::new(dat),
+            _mode: core::marker::PhantomData,
+        }
+    }
+
+    #[cfg_attr(feature = "lockdep", track_caller)]
+    pub fn read(&self) -> spin_rwlock_readguard<'_, T, MODE> {
+        let prev_xie = MODE::cli();
+
+        #[cfg(feature = "lockdep")]
+        lockdep::acquire(self as *const _ as usize, false, Location::caller());
+
+        spin_rwlock_readguard::<T, MODE> {
+            dat: self.inner_lock.read(),
+            old_xie: prev_xie,
+            #[cfg(feature = "lockdep")]
+            class: self as *const _ as usize,
+            _mode: core::marker::PhantomData,
+        }
+    }
+
+    #[cfg_attr(feature = "lockdep", track_caller)]
+    pub fn write(&self) -> spin_rwlock_writeguard<'_, T, MODE> {
+        let prev_xie = MODE::cli();
+
+        #[cfg(feature = "lockdep")]
+        lockdep::acquire(self as *const _ as usize, true, Location::caller());
+
+        spin_rwlock_writeguard::<T, MODE> {
+            dat: self.inner_lock.write(),
+            old_xie: prev_xie,
+            #[cfg(feature = "lockdep")]
+            class: self as *const _ as usize,
+            _mode: core::marker::PhantomData,
+        }
+    }
+}
+
+pub struct spin_rwlock_readguard<'a, T, MODE: IntControl> {
+    pub dat: spin::RwLockReadGuard<'a, T>,
+    old_xie: usize,
+    #[cfg(feature = "lockdep")]
+    class: usize,
+    _mode: core::marker::PhantomData<MODE>,
+}
+
+impl<T, MODE: IntControl> Drop for spin_rwlock_readguard<'_, T, MODE> {
+    fn drop(&mut self) {
+        #[cfg(feature = "lockdep")]
+        lockdep::release(self.class);
+
+        MODE::sti(self.old_xie);
+    }
+}
+
+impl<'a, T, MODE: IntControl> Deref for spin_rwlock_readguard<'a, T, MODE> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        &self.dat
+    }
+}
+
+pub struct spin_rwlock_writeguard<'a, T, MODE: IntControl> {
+    pub dat: spin::RwLockWriteGuard<'a, T>,
+    old_xie: usize,
+    #[cfg(feature = "lockdep")]
+    class: usize,
+    _mode: core::marker::PhantomData<MODE>,
+}
+
+impl<T, MODE: IntControl> Drop for spin_rwlock_writeguard<'_, T, MODE> {
+    fn drop(&mut self) {
+        #[cfg(feature = "lockdep")]
+        lockdep::release(self.class);
+
+        MODE::sti(self.old_xie);
+    }
+}
+
+impl<'a, T, MODE: IntControl> Deref for spin_rwlock_writeguard<'a, T, MODE> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        &self.dat
+    }
+}
+
+impl<'a, T, MODE: IntControl> DerefMut for spin_rwlock_writeguard<'a, T, MODE> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.dat
+    }
+}
+
+/*
+ * Lock dependency checker, built with feature "lockdep". Class of a lock is its address,
+ * so every array slot and every lock living inside an object is a class of its own.
+ *
+ * Each hart keeps a stack of what it is holding. Taking lock B while holding A records
+ * edge A -> B, a new edge that closes a cycle is a lock order inversion. Taking a lock
+ * this hart already holds is reported as well, unless both are read locks.
+ *
+ * Holding stacks are per hart rather than per task, a task preempted with a spin lock
+ * held leaves it on the stack of its old hart. A lock freed and its memory reused keeps
+ * the edges of the old one
+ */
+#[cfg(feature = "lockdep")]
+mod lockdep {
+    use crate::cpu::{which_cpu, MAX_HARTS};
+    use crate::{Mprintln, M_UART};
+    use core::panic::Location;
+    use core::sync::atomic::{AtomicBool, Ordering};
+    use spin::Mutex;
+
+    const MAX_HELD: usize = 16;
+    const MAX_DEPS: usize = 256;
+
+    #[derive(Clone, Copy)]
+    struct held_lock {
+        class: usize,
+        exclusive: bool,
+        site: &'static Location<'static>,
+    }
+
+    /*
+     * after was taken at site while before was held
+     */
+    #[derive(Clone, Copy)]
+    struct lock_dep {
+        before: usize,
+        after: usize,
+        site: &'static Location<'static>,
+    }
 
-// pub struct irq_rwlock_writeguard<'a, T> {
-//     pub dat: Option<spin::RwLockWriteGuard<'a, T>>,
-// }
+    struct held_stack {
+        locks: [Option<held_lock>; MAX_HELD],
+        depth: usize,
+    }
 
-// impl<T> irq_rwlock<T> {
-//     pub const fn new(dat: T) -> Self{
-//         Self{
-//             inner_lock: RwLock::new(dat),
-//         }
-//     }
+    impl held_stack {
+        const fn new() -> Self {
+            held_stack {
+                locks: [None; MAX_HELD],
+                depth: 0,
+            }
+        }
+    }
 
-//     pub fn write(&self) -> irq_rwlock_writeguard<'_, T> {
-//         cli();
+    struct dep_graph {
+        deps: [Option<lock_dep>; MAX_DEPS],
+        cnt: usize,
+        /*
+         * Scratch space of find_path(), kept here instead of on a trap stack
+         */
+        visited: [bool; MAX_DEPS],
+        todo: [usize; MAX_DEPS],
+        first: [usize; MAX_DEPS],
+    }
 
-//         let guard = self.inner_lock.write();
+    impl dep_graph {
+        const fn new() -> Self {
+            dep_graph {
+                deps: [None; MAX_DEPS],
+                cnt: 0,
+                visited: [false; MAX_DEPS],
+                todo: [0; MAX_DEPS],
+                first: [0; MAX_DEPS],
+            }
+        }
 
-//         irq_rwlock_writeguard{
-//             dat: Some(guard)
-//         }
-//     }
+        fn dep(&self, idx: usize) -> lock_dep {
+            self.deps[idx].unwrap()
+        }
 
-//     pub fn read(&self) -> irq_rwlock_readguard<'_, T> {
-//         cli();
+        fn has_dep(&self, before: usize, after: usize) -> bool {
+            (0..self.cnt).any(|i| {
+                let dep = self.dep(i);
+                dep.before == before && dep.after == after
+            })
+        }
 
-//         let guard = self.inner_lock.read();
+        /*
+         * First edge of some path from -> ... -> to
+         */
+        fn find_path(&mut self, from: usize, to: usize) -> Option<lock_dep> {
+            let mut top = 0;
+            for i in 0..self.cnt {
+                self.visited[i] = self.dep(i).before == from;
+                if self.visited[i] {
+                    self.first[i] = i;
+                    self.todo[top] = i;
+                    top += 1;
+                }
+            }
+
+            while top > 0 {
+                top -= 1;
+                let cur = self.todo[top];
+                let reached = self.dep(cur).after;
+                if reached == to {
+                    return Some(self.dep(self.first[cur]));
+                }
+
+                for i in 0..self.cnt {
+                    if !self.visited[i] && self.dep(i).before == reached {
+                        self.visited[i] = true;
+                        self.first[i] = self.first[cur];
+                        self.todo[top] = i;
+                        top += 1;
+                    }
+                }
+            }
+
+            None
+        }
 
-//         irq_rwlock_readguard{
-//             dat: guard
-//         }
-//     }
-// }
+        fn add_dep(&mut self, dep: lock_dep) -> bool {
+            if self.cnt == MAX_DEPS {
+                return false;
+            }
 
-// impl<T> Drop for irq_rwlock_writeguard<'_, T>{
-//     fn drop(&mut self) {
-//         sti()
-//     }
-// }
+            self.deps[self.cnt] = Some(dep);
+            self.cnt += 1;
+            true
+        }
+    }
 
-// impl <'a, T> Deref for irq_rwlock_writeguard<'a, T>{
-//     type Target = T;
+    static mut HELD: [held_stack; MAX_HARTS] = [const { held_stack::new() }; MAX_HARTS];
+    static DEPS: Mutex<dep_graph> = Mutex::new(dep_graph::new());
 
-//     fn deref(&self) -> &Self::Target{
-//         &self.dat.as_ref().unwrap()
-//     }
-// }
+    /*
+     * Set while a hart is inside the checker. Locks taken meanwhile, by Mprintln!() or by
+     * a trap landing in the middle, are not tracked
+     */
+    static IN_LOCKDEP: [AtomicBool; MAX_HARTS] = [const { AtomicBool::new(false) }; MAX_HARTS];
+    static DEPS_FULL: AtomicBool = AtomicBool::new(false);
 
-// impl <'a, T> DerefMut for irq_rwlock_writeguard<'a, T>{
-//     fn deref_mut(&mut self) -> &mut Self::Target{
-//         self.dat.as_mut().unwrap()
-//     }
-// }
+    pub fn acquire(class: usize, exclusive: bool, site: &'static Location<'static>) {
+        let hart = which_cpu();
+        if IN_LOCKDEP[hart].swap(true, Ordering::Acquire) {
+            return;
+        }
 
-// pub struct irq_rwlock_readguard<'a, T> {
-//     pub dat: spin::RwLockReadGuard<'a, T>,
-// }
+        let held = unsafe { &mut HELD[hart] };
+
+        for h in held.locks[..held.depth].iter().flatten() {
+            if h.class == class && (exclusive || h.exclusive) {
+                Mprintln!(
+                    "lockdep: CPU#{} taking lock {:#x} at {}, already held since {}",
+                    hart,
+                    class,
+                    site,
+                    h.site
+                );
+            }
+        }
+
+        for h in held.locks[..held.depth].iter().flatten() {
+            if h.class == class {
+                continue;
+            }
+
+            let mut deps = DEPS.lock();
+            if deps.has_dep(h.class, class) {
+                continue;
+            }
+
+            if let Some(rev) = deps.find_path(class, h.class) {
+                drop(deps);
+                Mprintln!(
+                    "lockdep: CPU#{} lock order inversion, taking {:#x} at {} while holding {:#x} taken at {}",
+                    hart,
+                    class,
+                    site,
+                    h.class,
+                    h.site
+                );
+                Mprintln!(
+                    "lockdep: but {:#x} was taken at {} while holding {:#x}",
+                    rev.after,
+                    rev.site,
+                    rev.before
+                );
+                continue;
+            }
+
+            let added = deps.add_dep(lock_dep {
+                before: h.class,
+                after: class,
+                site,
+            });
+            drop(deps);
+
+            if !added && !DEPS_FULL.swap(true, Ordering::Relaxed) {
+                Mprintln!("lockdep: dependency table full, new lock orders are not checked");
+            }
+        }
+
+        if held.depth < MAX_HELD {
+            held.locks[held.depth] = Some(held_lock {
+                class,
+                exclusive,
+                site,
+            });
+            held.depth += 1;
+        } else {
+            Mprintln!(
+                "lockdep: CPU#{} holding more than {} locks, {:#x} at {} not tracked",
+                hart,
+                MAX_HELD,
+                class,
+                site
+            );
+        }
+
+        IN_LOCKDEP[hart].store(false, Ordering::Release);
+    }
+
+    /*
+     * Guards may be dropped in any order, latest entry of class goes away
+     */
+    pub fn release(class: usize) {
+        let hart = which_cpu();
+        if IN_LOCKDEP[hart].swap(true, Ordering::Acquire) {
+            return;
+        }
 
-// impl<T> Drop for irq_rwlock_readguard<'_, T> {
-//     fn drop(&mut self) {
-//         sti()
-//     }
-// }
+        let held = unsafe { &mut HELD[hart] };
+        let found = held.locks[..held.depth]
+            .iter()
+            .rposition(|h| matches!(h, Some(h) if h.class == class));
 
-// impl <'a, T> Deref for irq_rwlock_readguard<'a, T>{
-//     type Target = T;
+        if let Some(idx) = found {
+            held.locks.copy_within(idx + 1..held.depth, idx);
+            held.depth -= 1;
+            held.locks[held.depth] = None;
+        }
 
-//     fn deref(&self) -> &Self::Target{
-//         &self.dat
-//     }
-// }
+        IN_LOCKDEP[hart].store(false, Ordering::Release);
+    }
+}
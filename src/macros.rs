@@ -6,7 +6,7 @@ macro_rules! Mprint
 {
     ($($args:tt)+) => ({
         use core::fmt::Write;
-        use crate::cpu;
+        use $crate::cpu;
         let _ = write!(M_UART.lock(), $($args)+);
     });
 }
@@ -15,17 +15,17 @@ macro_rules! Mprint
 macro_rules! Mprintln
 {
     () => ({
-        use crate::Mprint;
+        use $crate::Mprint;
         Mprint!("\r\n")
     });
 
     ($fmt:expr) => ({
-        use crate::Mprint;
+        use $crate::Mprint;
         Mprint!(concat!($fmt, "\r\n"))
     });
 
     ($fmt:expr, $($args:tt)+) => ({
-        use crate::Mprint;
+        use $crate::Mprint;
         Mprint!(concat!($fmt, "\r\n"), $($args)+)
     });
 
@@ -36,7 +36,7 @@ macro_rules! Sprint
 {
     ($($args:tt)+) => ({
         use core::fmt::Write;
-        use crate::cpu;
+        use $crate::cpu;
         let _ = write!(S_UART.lock(), $($args)+);
     });
 }
@@ -45,17 +45,17 @@ macro_rules! Sprint
 macro_rules! Sprintln
 {
     () => ({
-        use crate::Sprint;
+        use $crate::Sprint;
         Sprint!("\r\n")
     });
 
     ($fmt:expr) => ({
-        use crate::Sprint;
+        use $crate::Sprint;
         Sprint!(concat!($fmt, "\r\n"))
     });
 
     ($fmt:expr, $($args:tt)+) => ({
-        use crate::Sprint;
+        use $crate::Sprint;
         Sprint!(concat!($fmt, "\r\n"), $($args)+)
     });
 
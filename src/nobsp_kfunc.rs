@@ -8,7 +8,7 @@ use spin::Mutex;
 use crate::cpu::{which_cpu, SATP_mode, TrapFrame};
 use crate::error::{KError, KErrorType};
 use crate::ktask::{ktask_extint, KHello_task0, KHello_task1};
-use crate::kthread::{task_flag, task_struct};
+use crate::kthread::{task_flag, task_struct, DEF_PRIO};
 use crate::lock::spin_mutex;
 use crate::lock::{M_lock, S_lock};
 use crate::page;
@@ -18,7 +18,7 @@ use crate::zone::{kfree_page, kmalloc_page, zone_type};
 use crate::CLINT;
 use crate::KTHREAD_POOL;
 
-use crate::{cpu, kmem, vm, KERNEL_TRAP_FRAME, M_UART, S_UART};
+use crate::{asid, cpu, ipi, kmem, ktimer, vm, KERNEL_TRAP_FRAME, M_UART, S_UART};
 
 pub fn kinit() -> Result<usize, KError> {
     let current_cpu = which_cpu();
@@ -28,9 +28,9 @@ pub fn kinit() -> Result<usize, KError> {
     let pageroot_ptr = kmem::get_page_table();
     let mut pageroot = unsafe { pageroot_ptr.as_mut().unwrap() };
 
-    cpu::satp_write(SATP_mode::Sv39, 0, pageroot_ptr as usize);
+    cpu::satp_write(SATP_mode::Sv39, asid::ASID_KERN, pageroot_ptr as usize);
 
-    cpu::mepc_write(crate::eh_func_nobsp_kmain as usize);
+    cpu::mepc_write(crate::eh_func_nobsp_kmain as *const () as usize);
 
     // cpu::mstatus_write((1 << 11) | (1 << 5) as usize);
 
@@ -47,8 +47,9 @@ pub fn kinit() -> Result<usize, KError> {
         CLINT.set_mtimecmp(current_cpu, u64::MAX);
 
         mie::set_msoft();
+        ipi::set_online(current_cpu);
 
-        // mie::set_mtimer();
+        mie::set_mtimer();
 
         mie::set_mext();
 
@@ -72,21 +73,30 @@ pub fn kmain() -> Result<(), KError> {
         asm!("ebreak");
 
         Sprintln!("CPU{} Back from trap\n", current_cpu);
-        CLINT.set_mtimecmp(current_cpu, CLINT.read_mtime() + 0x500_000);
+        ktimer::arm_slice(current_cpu);
 
         let sched_cpu = which_cpu();
 
-        // KTHREAD_POOL.spawn(KHello_task0 as usize, task_flag::NORMAL, sched_cpu)?;
-        KTHREAD_POOL.spawn(KHello_task1 as usize, task_flag::NORMAL, sched_cpu)?;
-        KTHREAD_POOL.spawn(ktask_extint as usize, task_flag::CRITICAL, sched_cpu)?;
+        // KTHREAD_POOL.spawn(KHello_task0 as usize, task_flag::NORMAL, DEF_PRIO, sched_cpu)?;
+        KTHREAD_POOL.spawn(
+            KHello_task1 as *const () as usize,
+            task_flag::NORMAL,
+            DEF_PRIO,
+            sched_cpu,
+        )?;
+        KTHREAD_POOL.spawn_pinned(
+            ktask_extint as *const () as usize,
+            task_flag::CRITICAL,
+            DEF_PRIO,
+            sched_cpu,
+        )?;
         KTHREAD_POOL.join_all_ktask(sched_cpu);
     }
 
     loop {
-        Sprintln!("CPU#{} kmain keep running...", current_cpu);
-        let _ = cpu::busy_delay(1);
+        //Not suppose to reach here
         unsafe {
-            asm!("nop");
+            asm!("wfi");
         }
     }
 }
@@ -140,12 +140,12 @@ impl page_allocator for naive_allocator {
         for i in 0..self.tot_page {
             match self.map_first_fit_avail(i, pg_cnt) {
                 Ok(res) => {
-                    if res == true {
+                    if res {
                         self.map_mark_taken(i, pg_cnt);
 
                         alloc_addr = (self.mem_begin + (i * PAGE_SIZE)) as *const u8;
 
-                        if let Some(_) = self.pagetree {
+                        if self.pagetree.is_some() {
                             for pg_idx in 0..pg_cnt {
                                 unsafe {
                                     self.pagetree_update(&PageRec {
@@ -192,32 +192,71 @@ impl page_allocator for naive_allocator {
     }
 
     /*
-     * NOTE
-     * alloc_pages() can allocate multiple continuous paddr pages, but free_pages can only free one
-     * page at a time.
+     * free_pages() takes the same pg_cnt which was handed to alloc_pages(). Reference count
+     * lives on the first page of the run, the run goes back to the map once nobody refers
+     * to it. Whole run is checked before anything is touched, so a bad page in the middle
+     * can't leave it half freed
      */
-    fn free_pages(&mut self, addr: *mut u8) -> Result<(), KError> {
+    fn free_pages(&mut self, addr: *mut u8, pg_cnt: usize) -> Result<(), KError> {
         // Mprintln!("Start reclaiming...");
+        if pg_cnt == 0 || (addr as usize) < self.mem_begin || (addr as usize) & (PAGE_SIZE - 1) != 0
+        {
+            return Err(new_kerror!(KErrorType::EINVAL));
+        }
+
+        let free_begin_pgnum = (addr as usize - self.mem_begin) / PAGE_SIZE;
+        if free_begin_pgnum + pg_cnt > self.tot_page {
+            return Err(new_kerror!(KErrorType::EINVAL));
+        }
+
         let pfn = addr2pfn!(addr as usize);
+        if (0..pg_cnt).any(|pg_idx| self.pagetree_get(pfn + pg_idx).is_none()) {
+            return Err(new_kerror!(KErrorType::EFAULT));
+        }
+
         let refcnt = self
             .pagetree_getrefcnt(pfn)
             .ok_or(new_kerror!(KErrorType::EFAULT))?;
-
         if refcnt > 1 {
             self.pagetree_setrefcnt(pfn, refcnt - 1);
-            Ok(())
-        } else {
-            let mut free_begin_pgnum: usize;
-            let mut free_pgnum: usize;
-
-            free_begin_pgnum = (addr as usize - self.mem_begin) / PAGE_SIZE;
+            return Ok(());
+        }
 
-            self.map_mark_free(free_begin_pgnum, 1);
+        self.map_mark_free(free_begin_pgnum, pg_cnt);
+        for pg_idx in 0..pg_cnt {
+            self.pagetree_remove(pfn + pg_idx)?;
+        }
 
-            self.pagetree_remove(pfn);
+        Ok(())
+    }
 
-            Ok(())
+    fn reserve_pages(&mut self, begin: usize, end: usize) -> Result<(), KError> {
+        let rsv_begin = aligl_4k!(begin).max(self.mem_begin);
+        let rsv_end = aligh_4k!(end).min(self.mem_end);
+        if rsv_begin >= rsv_end {
+            return Ok(());
         }
+
+        self.map_mark_taken(
+            (rsv_begin - self.mem_begin) / PAGE_SIZE,
+            (rsv_end - rsv_begin) / PAGE_SIZE,
+        );
+
+        Ok(())
+    }
+
+    fn page_refcnt(&self, addr: *mut u8) -> Result<usize, KError> {
+        self.pagetree_getrefcnt(addr2pfn!(addr as usize))
+            .ok_or(new_kerror!(KErrorType::EFAULT))
+    }
+
+    fn get_page(&mut self, addr: *mut u8) -> Result<usize, KError> {
+        let pfn = addr2pfn!(addr as usize);
+        let refcnt = self
+            .pagetree_getrefcnt(pfn)
+            .ok_or(new_kerror!(KErrorType::EFAULT))?;
+        self.pagetree_setrefcnt(pfn, refcnt + 1);
+        Ok(refcnt + 1)
     }
 }
 
@@ -239,12 +278,12 @@ impl naive_allocator {
         Mprintln!("Total Pages: {}", self.tot_page);
         Mprintln!(
             "Mapping Begin: {:#x} -- Size: {:#x}",
-            self.map_begin as usize,
+            self.map_begin,
             self.map_size
         );
         Mprintln!(
             "Memory Begin: {:#x} -- Size: {:#x}",
-            self.mem_begin as usize,
+            self.mem_begin,
             self.tot_page * 4096
         );
         Mprintln!("------------Allocator Info End------------");
@@ -269,7 +308,7 @@ impl naive_allocator {
 
     fn map_mark_taken(&mut self, map_off: usize, page_cnt: usize) {
         let mut mark_begin = self.map_begin as *mut pgalloc_mark;
-        mark_begin = mark_begin.wrapping_offset(map_off as isize);
+        mark_begin = mark_begin.wrapping_add(map_off);
         for i in 0..page_cnt {
             unsafe {
                 mark_begin.add(i).write(pgalloc_mark {
@@ -281,7 +320,7 @@ impl naive_allocator {
 
     fn map_mark_free(&mut self, map_off: usize, page_cnt: usize) {
         let mut mark_begin = self.map_begin as *mut pgalloc_mark;
-        mark_begin = mark_begin.wrapping_offset(map_off as isize);
+        mark_begin = mark_begin.wrapping_add(map_off);
         for i in 0..page_cnt {
             unsafe {
                 mark_begin.add(i).write(pgalloc_mark {
@@ -342,6 +381,408 @@ impl naive_allocator {
     }
 }
 
+/*
+ * buddy_allocator keeps one buddy_mark per page inside the metadata area, free lists are
+ * doubly linked through those marks by page index, so we never need to touch the free pages
+ * themselves (they are not mapped in S-mode page table)
+ *
+ * Invariant: a mark is FREE if and only if that page is the head of a block which sits in
+ * free_area[order]. Allocated block heads are TAKEN with their order recorded, every other
+ * page is TAKEN with BUDDY_NOORDER
+ */
+pub const BUDDY_MAX_ORDER: usize = 10;
+const BUDDY_NIL: u32 = u32::MAX;
+const BUDDY_NOORDER: u8 = u8::MAX;
+
+struct buddy_mark {
+    flags: pgalloc_flags,
+    order: u8,
+    prev: u32,
+    next: u32,
+}
+
+pub struct buddy_allocator {
+    tot_page: usize,
+    zone_begin: usize,
+    zone_end: usize,
+    map_begin: usize,
+    map_size: usize,
+    mem_begin: usize,
+    mem_end: usize,
+    free_area: [u32; BUDDY_MAX_ORDER + 1],
+    free_cnt: [usize; BUDDY_MAX_ORDER + 1],
+    pagetree: Option<BTreeMap<usize, PageRec>>,
+}
+
+impl page_allocator for buddy_allocator {
+    fn allocator_init(
+        &mut self,
+        zone_start: usize,
+        zone_end: usize,
+        zone_size: usize,
+    ) -> Result<(usize, usize), KError> {
+        Mprintln!("buddy_Allocator Initializing");
+        if zone_size < 3 * PAGE_SIZE {
+            return Err(new_kerror!(KErrorType::ENOMEM));
+        }
+
+        let bmark_sz = mem::size_of::<buddy_mark>();
+
+        self.zone_begin = zone_start;
+        self.zone_end = zone_end;
+
+        self.mem_end = aligl_4k!(zone_end);
+        self.map_begin = aligh_4k!(zone_start);
+
+        self.tot_page = (self.mem_end - self.map_begin) / PAGE_SIZE;
+        self.map_size = aligh_4k!(self.tot_page * bmark_sz);
+        self.mem_begin = self.map_begin + self.map_size;
+        self.tot_page = (self.mem_end - self.mem_begin) / PAGE_SIZE;
+
+        if self.tot_page >= BUDDY_NIL as usize {
+            return Err(new_kerror!(KErrorType::EINVAL));
+        }
+
+        for pg_idx in 0..self.tot_page {
+            self.mark_clear(pg_idx);
+        }
+
+        /*
+         * Carve the zone into the largest naturally aligned blocks, tail of the zone
+         * usually ends up as a bunch of lower order blocks
+         */
+        let mut pg_idx = 0;
+        while pg_idx < self.tot_page {
+            let mut order = BUDDY_MAX_ORDER;
+            while (pg_idx & ((1 << order) - 1)) != 0 || pg_idx + (1 << order) > self.tot_page {
+                order -= 1;
+            }
+            self.freelist_push(pg_idx, order);
+            pg_idx += 1 << order;
+        }
+
+        self.print_info();
+
+        Ok((self.map_begin, self.mem_begin))
+    }
+
+    fn alloc_pages(&mut self, pg_cnt: usize) -> Result<*mut u8, KError> {
+        if pg_cnt == 0 {
+            return Err(new_kerror!(KErrorType::EINVAL));
+        }
+
+        let order = pgcnt2order(pg_cnt);
+        if order > BUDDY_MAX_ORDER {
+            return Err(new_kerror!(KErrorType::ENOMEM));
+        }
+
+        let mut cur_order = order;
+        while cur_order <= BUDDY_MAX_ORDER && self.free_area[cur_order] == BUDDY_NIL {
+            cur_order += 1;
+        }
+        if cur_order > BUDDY_MAX_ORDER {
+            return Err(new_kerror!(KErrorType::ENOMEM));
+        }
+
+        let pg_idx = self.free_area[cur_order] as usize;
+        self.freelist_remove(pg_idx, cur_order);
+
+        while cur_order > order {
+            cur_order -= 1;
+            self.freelist_push(pg_idx + (1 << cur_order), cur_order);
+        }
+
+        let head = self.mark_at(pg_idx);
+        head.flags = pgalloc_flags::TAKEN;
+        head.order = order as u8;
+
+        let alloc_addr = (self.mem_begin + (pg_idx * PAGE_SIZE)) as *const u8;
+
+        if self.pagetree.is_some() {
+            for pg_off in 0..pg_cnt {
+                unsafe {
+                    self.pagetree_update(&PageRec {
+                        pfn: addr2pfn!(alloc_addr.add(PAGE_SIZE * pg_off) as usize),
+                        refcnt: 1,
+                        flag: PageFlags::DEFAULT,
+                    })?;
+                }
+            }
+        } else {
+            /*
+             * Same trick as naive_allocator: the very first allocation is kheap itself, so
+             * kheap has to be settled before we are able to build pagetree
+             */
+            let kheap_pgcnt = get_kheap_pgcnt();
+            set_kheap_start(alloc_addr as *mut u8);
+            let kheap_begin = get_kheap_start();
+            unsafe {
+                cust_hmalloc
+                    .lock()
+                    .init(kheap_begin as usize, kheap_pgcnt * PAGE_SIZE);
+            }
+            self.pagetree_init();
+
+            for pg_off in 0..kheap_pgcnt {
+                unsafe {
+                    self.pagetree_update(&PageRec {
+                        pfn: addr2pfn!(alloc_addr.add(PAGE_SIZE * pg_off) as usize),
+                        refcnt: 1,
+                        flag: PageFlags::DEFAULT,
+                    })?;
+                }
+            }
+        }
+
+        Ok(alloc_addr as *mut u8)
+    }
+
+    /*
+     * pg_cnt has to round up to the same order as the one used by alloc_pages(), otherwise
+     * caller is trying to free part of a block
+     */
+    fn free_pages(&mut self, addr: *mut u8, pg_cnt: usize) -> Result<(), KError> {
+        let addr = addr as usize;
+        if addr < self.mem_begin || addr >= self.mem_end || addr & (PAGE_SIZE - 1) != 0 {
+            return Err(new_kerror!(KErrorType::EINVAL));
+        }
+
+        let mut pg_idx = (addr - self.mem_begin) / PAGE_SIZE;
+        let head = self.mark_at(pg_idx);
+        if matches!(head.flags, pgalloc_flags::FREE) || head.order == BUDDY_NOORDER {
+            return Err(new_kerror!(KErrorType::EFAULT));
+        }
+        let mut order = head.order as usize;
+        if pg_cnt == 0 || pgcnt2order(pg_cnt) != order {
+            return Err(new_kerror!(KErrorType::EINVAL));
+        }
+
+        let pfn = addr2pfn!(addr);
+        let refcnt = self
+            .pagetree_getrefcnt(pfn)
+            .ok_or(new_kerror!(KErrorType::EFAULT))?;
+
+        if refcnt > 1 {
+            self.pagetree_setrefcnt(pfn, refcnt - 1);
+            return Ok(());
+        }
+
+        for pg_off in 0..(1 << order) {
+            self.pagetree_remove(pfn + pg_off);
+        }
+        self.mark_clear(pg_idx);
+
+        while order < BUDDY_MAX_ORDER {
+            let buddy_idx = pg_idx ^ (1 << order);
+            if buddy_idx + (1 << order) > self.tot_page {
+                break;
+            }
+
+            let buddy = self.mark_at(buddy_idx);
+            if !matches!(buddy.flags, pgalloc_flags::FREE) || buddy.order as usize != order {
+                break;
+            }
+
+            self.freelist_remove(buddy_idx, order);
+            pg_idx &= buddy_idx;
+            order += 1;
+        }
+
+        self.freelist_push(pg_idx, order);
+
+        Ok(())
+    }
+
+    /*
+     * Reserved pages are pulled out of their free blocks one by one, they end up TAKEN
+     * with BUDDY_NOORDER so free_pages() refuses to give them back
+     */
+    fn reserve_pages(&mut self, begin: usize, end: usize) -> Result<(), KError> {
+        let rsv_begin = aligl_4k!(begin).max(self.mem_begin);
+        let rsv_end = aligh_4k!(end).min(self.mem_begin + self.tot_page * PAGE_SIZE);
+        if rsv_begin >= rsv_end {
+            return Ok(());
+        }
+
+        let first_pg = (rsv_begin - self.mem_begin) / PAGE_SIZE;
+        let last_pg = (rsv_end - self.mem_begin) / PAGE_SIZE;
+        for pg_idx in first_pg..last_pg {
+            self.reserve_one(pg_idx);
+        }
+
+        Ok(())
+    }
+
+    fn page_refcnt(&self, addr: *mut u8) -> Result<usize, KError> {
+        self.pagetree_getrefcnt(addr2pfn!(addr as usize))
+            .ok_or(new_kerror!(KErrorType::EFAULT))
+    }
+
+    fn get_page(&mut self, addr: *mut u8) -> Result<usize, KError> {
+        let pfn = addr2pfn!(addr as usize);
+        let refcnt = self
+            .pagetree_getrefcnt(pfn)
+            .ok_or(new_kerror!(KErrorType::EFAULT))?;
+        self.pagetree_setrefcnt(pfn, refcnt + 1);
+        Ok(refcnt + 1)
+    }
+}
+
+fn pgcnt2order(pg_cnt: usize) -> usize {
+    pg_cnt.next_power_of_two().trailing_zeros() as usize
+}
+
+impl buddy_allocator {
+    pub const fn new() -> Self {
+        buddy_allocator {
+            tot_page: 0,
+            zone_begin: 0,
+            zone_end: 0,
+            map_begin: 0,
+            map_size: 0,
+            mem_begin: 0,
+            mem_end: 0,
+            free_area: [BUDDY_NIL; BUDDY_MAX_ORDER + 1],
+            free_cnt: [0; BUDDY_MAX_ORDER + 1],
+            pagetree: None,
+        }
+    }
+
+    fn print_info(&self) {
+        Mprintln!("------------Allocator Info------------");
+        Mprintln!("Total Pages: {}", self.tot_page);
+        Mprintln!(
+            "Mapping Begin: {:#x} -- Size: {:#x}",
+            self.map_begin,
+            self.map_size
+        );
+        Mprintln!(
+            "Memory Begin: {:#x} -- Size: {:#x}",
+            self.mem_begin,
+            self.tot_page * PAGE_SIZE
+        );
+        for order in 0..=BUDDY_MAX_ORDER {
+            Mprintln!("Order {}: {} free block(s)", order, self.free_cnt[order]);
+        }
+        Mprintln!("------------Allocator Info End------------");
+    }
+
+    fn mark_at(&mut self, pg_idx: usize) -> &mut buddy_mark {
+        let rawpt_mapbegin = self.map_begin as *mut buddy_mark;
+        unsafe { rawpt_mapbegin.add(pg_idx).as_mut().unwrap() }
+    }
+
+    fn mark_clear(&mut self, pg_idx: usize) {
+        let rawpt_mapbegin = self.map_begin as *mut buddy_mark;
+        unsafe {
+            rawpt_mapbegin.add(pg_idx).write(buddy_mark {
+                flags: pgalloc_flags::TAKEN,
+                order: BUDDY_NOORDER,
+                prev: BUDDY_NIL,
+                next: BUDDY_NIL,
+            })
+        }
+    }
+
+    /*
+     * Split the free block holding pg_idx until pg_idx becomes an order 0 block of its own,
+     * then take it off the list. Pages which are already taken are left alone
+     */
+    fn reserve_one(&mut self, pg_idx: usize) {
+        let mut blk_order = None;
+        for order in 0..=BUDDY_MAX_ORDER {
+            let head_idx = pg_idx & !((1 << order) - 1);
+            let head = self.mark_at(head_idx);
+            if matches!(head.flags, pgalloc_flags::FREE) && head.order as usize == order {
+                blk_order = Some(order);
+                break;
+            }
+        }
+
+        let Some(mut order) = blk_order else {
+            return;
+        };
+
+        let mut head_idx = pg_idx & !((1 << order) - 1);
+        self.freelist_remove(head_idx, order);
+
+        while order > 0 {
+            order -= 1;
+            let upper_idx = head_idx + (1 << order);
+            if pg_idx >= upper_idx {
+                self.freelist_push(head_idx, order);
+                head_idx = upper_idx;
+            } else {
+                self.freelist_push(upper_idx, order);
+            }
+        }
+    }
+
+    fn freelist_push(&mut self, pg_idx: usize, order: usize) {
+        let old_head = self.free_area[order];
+
+        let mark = self.mark_at(pg_idx);
+        mark.flags = pgalloc_flags::FREE;
+        mark.order = order as u8;
+        mark.prev = BUDDY_NIL;
+        mark.next = old_head;
+
+        if old_head != BUDDY_NIL {
+            self.mark_at(old_head as usize).prev = pg_idx as u32;
+        }
+
+        self.free_area[order] = pg_idx as u32;
+        self.free_cnt[order] += 1;
+    }
+
+    fn freelist_remove(&mut self, pg_idx: usize, order: usize) {
+        let mark = self.mark_at(pg_idx);
+        let (prev, next) = (mark.prev, mark.next);
+
+        if prev == BUDDY_NIL {
+            self.free_area[order] = next;
+        } else {
+            self.mark_at(prev as usize).next = next;
+        }
+
+        if next != BUDDY_NIL {
+            self.mark_at(next as usize).prev = prev;
+        }
+
+        self.mark_clear(pg_idx);
+        self.free_cnt[order] -= 1;
+    }
+
+    fn pagetree_init(&mut self) {
+        self.pagetree = Some(BTreeMap::<usize, PageRec>::new());
+    }
+
+    fn pagetree_update(&mut self, newpg: &PageRec) -> Result<(), KError> {
+        match self.pagetree {
+            Some(ref mut pgtree) => {
+                pgtree.insert(newpg.pfn, *newpg);
+                Ok(())
+            }
+            None => Err(new_kerror!(KErrorType::EFAULT)),
+        }
+    }
+
+    fn pagetree_remove(&mut self, pfn: usize) -> Option<PageRec> {
+        self.pagetree.as_mut()?.remove(&pfn)
+    }
+
+    fn pagetree_getrefcnt(&self, pfn: usize) -> Option<usize> {
+        self.pagetree.as_ref()?.get(&pfn).map(|pgrec| pgrec.refcnt)
+    }
+
+    fn pagetree_setrefcnt(&mut self, pfn: usize, newrefcnt: usize) -> Option<()> {
+        self.pagetree
+            .as_mut()?
+            .get_mut(&pfn)
+            .map(|pgrec| pgrec.refcnt = newrefcnt)
+    }
+}
+
 #[derive(Clone, Copy)]
 pub struct empty_allocator {
     place_holder: usize,
@@ -363,12 +804,21 @@ impl page_allocator for empty_allocator {
         zone_size: usize,
     ) -> Result<(usize, usize), KError> {
         Mprintln!("Placeholder Allocator Initializing");
-        Ok((0 as usize, 0 as usize))
+        Ok((0, 0))
     }
     fn alloc_pages(&mut self, pg_cnt: usize) -> Result<*mut u8, KError> {
         Err(new_kerror!(KErrorType::ENOSYS))
     }
-    fn free_pages(&mut self, addr: *mut u8) -> Result<(), KError> {
+    fn free_pages(&mut self, addr: *mut u8, pg_cnt: usize) -> Result<(), KError> {
+        Err(new_kerror!(KErrorType::ENOSYS))
+    }
+    fn reserve_pages(&mut self, begin: usize, end: usize) -> Result<(), KError> {
+        Err(new_kerror!(KErrorType::ENOSYS))
+    }
+    fn page_refcnt(&self, addr: *mut u8) -> Result<usize, KError> {
+        Err(new_kerror!(KErrorType::ENOSYS))
+    }
+    fn get_page(&mut self, addr: *mut u8) -> Result<usize, KError> {
         Err(new_kerror!(KErrorType::ENOSYS))
     }
 }
@@ -0,0 +1,80 @@
+use crate::error::{KError, KErrorType};
+use crate::kthread::MAX_KTHREADS;
+use crate::new_kerror;
+use cbitmap::bitmap::*;
+
+/*
+ * pid names a slot and is reused once its task is reaped, lifeid is the generation of
+ * that slot and never repeats. A handle of a reaped task stays invalid even after its
+ * pid goes to somebody else
+ */
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub struct task_handle {
+    pid: usize,
+    lifeid: usize,
+}
+
+impl task_handle {
+    pub const fn new(pid: usize, lifeid: usize) -> Self {
+        task_handle { pid, lifeid }
+    }
+
+    pub fn pid(&self) -> usize {
+        self.pid
+    }
+
+    pub fn lifeid(&self) -> usize {
+        self.lifeid
+    }
+}
+
+impl From<(usize, usize)> for task_handle {
+    fn from(id: (usize, usize)) -> Self {
+        task_handle::new(id.0, id.1)
+    }
+}
+
+impl From<task_handle> for (usize, usize) {
+    fn from(handle: task_handle) -> Self {
+        (handle.pid, handle.lifeid)
+    }
+}
+
+/*
+ * One pid space for kernel and user tasks, pid < MAX_KTHREADS
+ */
+pub struct pid_allocator {
+    pidmap: Bitmap<{ MAX_KTHREADS / 8 }>,
+    /*
+     * lifeid 0 is never handed out, it marks "no task"
+     */
+    next_lifeid: usize,
+}
+
+impl pid_allocator {
+    pub fn new() -> Self {
+        pid_allocator {
+            pidmap: Bitmap::new(),
+            next_lifeid: 1,
+        }
+    }
+
+    pub fn alloc(&mut self) -> Result<task_handle, KError> {
+        let pid = self
+            .pidmap
+            .find_first_zero()
+            .ok_or(new_kerror!(KErrorType::ENOMEM))?;
+        self.pidmap.set(pid);
+
+        let lifeid = self.next_lifeid;
+        self.next_lifeid += 1;
+
+        Ok(task_handle::new(pid, lifeid))
+    }
+
+    pub fn free(&mut self, pid: usize) {
+        if pid < MAX_KTHREADS {
+            self.pidmap.reset(pid);
+        }
+    }
+}
@@ -1,5 +1,3 @@
-use core::mem::variant_count;
-
 use crate::cpu::{get_cpu_mode, which_cpu, Mode, MAX_HARTS};
 use crate::lock::spin_mutex;
 use crate::lock::{M_lock, S_lock};
@@ -16,6 +14,7 @@ pub const MAX_INTCNT: usize = 53;
 pub enum extint_name {
     UNDEF,
     UART0,
+    VIRTIO,
 }
 
 #[get_set(default(inline_always, vis = "pub"), get_copy, set)]
@@ -37,33 +36,28 @@ impl extint_src {
     }
 }
 
-pub enum plic_ctx {
-    CORE0_M,
-    CORE1_M,
-    CORE2_M,
-    CORE3_M,
-    CORE0_S,
-    CORE1_S,
-    CORE2_S,
-    CORE3_S,
+/*
+ * PLIC context of one privilege level of one hart. QEMU virt gives hart n context 2n
+ * for M-mode and 2n + 1 for S-mode
+ */
+#[derive(Clone, Copy)]
+pub struct plic_ctx {
+    idx: usize,
 }
 
 impl plic_ctx {
-    pub fn index(&self) -> usize {
-        match self {
-            plic_ctx::CORE0_M => 0,
-            plic_ctx::CORE1_M => 2,
-            plic_ctx::CORE2_M => 4,
-            plic_ctx::CORE3_M => 6,
-            plic_ctx::CORE0_S => 1,
-            plic_ctx::CORE1_S => 3,
-            plic_ctx::CORE2_S => 5,
-            plic_ctx::CORE3_S => 7,
+    pub const fn machine(hartid: usize) -> Self {
+        plic_ctx { idx: 2 * hartid }
+    }
+
+    pub const fn supervisor(hartid: usize) -> Self {
+        plic_ctx {
+            idx: 2 * hartid + 1,
         }
     }
 
-    pub const fn max_ctx() -> usize {
-        variant_count::<plic_ctx>()
+    pub fn index(&self) -> usize {
+        self.idx
     }
 }
 
@@ -88,6 +82,14 @@ impl plic_controller {
         }
     }
 
+    pub fn set_base(&mut self, new_base: usize) {
+        self.base = new_base;
+        self.prio_base = new_base;
+        self.pend_base = new_base + 0x1000;
+        self.enable_base = new_base + 0x2000;
+        self.thres_base = new_base + 0x20_0000;
+    }
+
     pub fn set_prio(&mut self, src: &extint_src, new_prio: u32) -> Result<(), KError> {
         if new_prio > 7 {
             return Err(new_kerror!(KErrorType::EINVAL));
@@ -98,7 +100,7 @@ impl plic_controller {
 
         unsafe {
             let base_pt = self.prio_base as *mut u32;
-            base_pt.add(usz_src).write(new_prio as u32)
+            base_pt.add(usz_src).write(new_prio)
         }
 
         Ok(())
@@ -115,9 +117,9 @@ impl plic_controller {
         }
 
         if mmio_val != reg_val {
-            return Err(new_kerror!(KErrorType::EFAULT));
+            Err(new_kerror!(KErrorType::EFAULT))
         } else {
-            return Ok(reg_val);
+            Ok(reg_val)
         }
     }
 
@@ -129,22 +131,18 @@ impl plic_controller {
             let pend_reg: u32 = pend_pt.add(usz_src / 32).read();
             let mask: u32 = (1 << (usz_src % 32));
 
-            if pend_reg & mask == 0 {
-                return Ok(false);
-            } else {
-                return Ok(true);
-            }
+            Ok(pend_reg & mask != 0)
         }
     }
 
     pub fn enable(&self, ctx: plic_ctx, src: &extint_src) -> Result<(), KError> {
-        let usz_ctx = ctx.index() as usize;
+        let usz_ctx = ctx.index();
         let usz_src: usize = src.get_src_id() / 32;
         let mask: u32 = (1 << (src.get_src_id() % 32));
         unsafe {
             let enable_pt = (self.enable_base + (usz_ctx * 0x80 + usz_src)) as *mut u32;
             let mut enable_reg: u32 = enable_pt.read();
-            enable_reg = enable_reg | mask;
+            enable_reg |= mask;
             enable_pt.write(enable_reg);
         }
 
@@ -152,13 +150,13 @@ impl plic_controller {
     }
 
     pub fn disable(&self, ctx: plic_ctx, src: &extint_src) -> Result<(), KError> {
-        let usz_ctx = ctx.index() as usize;
+        let usz_ctx = ctx.index();
         let usz_src: usize = src.get_src_id() / 32;
         let mask: u32 = !(1 << (src.get_src_id() % 32));
         unsafe {
             let disable_pt = (self.enable_base + (usz_ctx * 0x80 + usz_src)) as *mut u32;
             let mut disable_reg: u32 = disable_pt.read();
-            disable_reg = disable_reg | mask;
+            disable_reg &= mask;
             disable_pt.write(disable_reg);
         }
 
@@ -168,7 +166,7 @@ impl plic_controller {
         if new_thres > 7 {
             return Err(new_kerror!(KErrorType::EFAULT));
         }
-        let usz_ctx = ctx.index() as usize;
+        let usz_ctx = ctx.index();
         let thres_base = self.thres_base as *mut u32;
 
         unsafe {
@@ -179,7 +177,7 @@ impl plic_controller {
     }
 
     pub fn claim(&self, ctx: &plic_ctx) -> Result<u32, KError> {
-        let usz_ctx = ctx.index() as usize;
+        let usz_ctx = ctx.index();
         let claim_base = self.thres_base as *mut u32;
         let mut claimed_int: u32;
 
@@ -192,7 +190,7 @@ impl plic_controller {
     }
 
     pub fn complete(&self, ctx: &plic_ctx, src: u32) -> Result<(), KError> {
-        let usz_ctx = ctx.index() as usize;
+        let usz_ctx = ctx.index();
         let claim_base = self.thres_base as *mut u32;
 
         unsafe {
@@ -203,27 +201,15 @@ impl plic_controller {
     }
 }
 
+pub fn id2plic_mctx(hartid: usize) -> plic_ctx {
+    plic_ctx::machine(hartid)
+}
+
 pub fn id2plic_ctx(hartid: usize) -> plic_ctx {
     let current_mode = get_cpu_mode(hartid);
     if matches!(current_mode, Mode::Machine | Mode::Machine_IRH) {
-        match hartid {
-            0 => plic_ctx::CORE0_M,
-            1 => plic_ctx::CORE1_M,
-            2 => plic_ctx::CORE2_M,
-            3 => plic_ctx::CORE3_M,
-            _ => {
-                panic!();
-            }
-        }
+        plic_ctx::machine(hartid)
     } else {
-        match hartid {
-            0 => plic_ctx::CORE0_S,
-            1 => plic_ctx::CORE1_S,
-            2 => plic_ctx::CORE2_S,
-            3 => plic_ctx::CORE3_S,
-            _ => {
-                panic!();
-            }
-        }
+        plic_ctx::supervisor(hartid)
     }
 }
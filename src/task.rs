@@ -1,4 +1,9 @@
-#[no_mangle]
-fn utask_0() {
-    loop {}
-}
+/*
+ * Prebuilt by `make user`, see src/user/uhello.S
+ */
+pub static UHELLO_ELF: &[u8] = include_bytes!("user/uhello.elf");
+
+/*
+ * Prebuilt by `make user`, see src/user/utest.S. Exits with number of the failed step
+ */
+pub static UTEST_ELF: &[u8] = include_bytes!("user/utest.elf");
@@ -0,0 +1,150 @@
+use crate::cpu::{get_cpu_mode, which_cpu, M_off_depth, Mode, MAX_HARTS};
+use crate::ipi::{ipi_msg, ipi_poll, online_harts, send_ipi};
+use crate::kmem::get_page_table;
+use crate::page::PAGE_SIZE;
+use core::arch::asm;
+use core::hint::spin_loop;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/*
+ * Ranges longer than this many pages are flushed as a whole
+ */
+const FLUSH_ALL_PAGES: usize = 32;
+
+const SATP_PPN_MASK: usize = (1 << 44) - 1;
+
+/*
+ * Root page table each hart switched to last time. Every switch flushes the incoming
+ * ASID, so translations left on other harts are never used again without a flush
+ */
+static LOADED_ROOT: [AtomicUsize; MAX_HARTS] = [const { AtomicUsize::new(0) }; MAX_HARTS];
+
+/*
+ * Outstanding acks of the shootdown each hart is waiting for
+ */
+static PENDING_ACK: [AtomicUsize; MAX_HARTS] = [const { AtomicUsize::new(0) }; MAX_HARTS];
+
+/*
+ * One shootdown request, end == 0 means the whole address space
+ */
+#[derive(Clone, Copy)]
+pub struct tlb_req {
+    pub asid: Option<usize>,
+    pub begin: usize,
+    pub end: usize,
+    pub from: usize,
+}
+
+pub fn satp2root(satp: usize) -> usize {
+    (satp & SATP_PPN_MASK) << 12
+}
+
+pub fn set_loaded_satp(hart: usize, satp: usize) {
+    LOADED_ROOT[hart].store(satp2root(satp), Ordering::Release);
+}
+
+/*
+ * sfence.vma on calling hart only. asid None flushes every address space, including
+ * global kernel mappings
+ */
+pub fn flush_local(asid: Option<usize>, begin: usize, end: usize) {
+    unsafe {
+        if end == 0 || end <= begin || (end - begin) / PAGE_SIZE > FLUSH_ALL_PAGES {
+            match asid {
+                Some(asid) => asm!("sfence.vma zero, {0}", in(reg) asid),
+                None => asm!("sfence.vma"),
+            }
+            return;
+        }
+
+        let mut vaddr = begin & !(PAGE_SIZE - 1);
+        while vaddr < end {
+            match asid {
+                Some(asid) => asm!("sfence.vma {0}, {1}", in(reg) vaddr, in(reg) asid),
+                None => asm!("sfence.vma {0}, zero", in(reg) vaddr),
+            }
+            vaddr += PAGE_SIZE;
+        }
+    }
+}
+
+/*
+ * Receiver side, called from ipi_handler()
+ */
+pub fn tlb_ack(req: &tlb_req) {
+    flush_local(req.asid, req.begin, req.end);
+    PENDING_ACK[req.from].fetch_sub(1, Ordering::AcqRel);
+}
+
+/*
+ * Flush [begin, end) of page table root everywhere it may be cached, and come back only
+ * after every target hart has flushed. Pages unmapped from root can be freed after this.
+ *
+ * Kernel page table is shared by every address space, so every online hart is a target
+ * for it. A user page table is only a target on harts which loaded it last
+ */
+pub fn tlb_shootdown(root: usize, asid: Option<usize>, begin: usize, end: usize) {
+    let self_id = which_cpu();
+    let is_kern = root == get_page_table() as usize;
+    let asid = if is_kern { None } else { asid };
+
+    flush_local(asid, begin, end);
+
+    let req = tlb_req {
+        asid,
+        begin,
+        end,
+        from: self_id,
+    };
+
+    let online = online_harts();
+    let mut sent = 0;
+    for (hart, loaded) in LOADED_ROOT.iter().enumerate() {
+        if hart == self_id || online & (1 << hart) == 0 {
+            continue;
+        }
+        if !is_kern && loaded.load(Ordering::Acquire) != root {
+            continue;
+        }
+
+        /*
+         * A full mailbox is drained by target's msip handler sooner or later
+         */
+        PENDING_ACK[self_id].fetch_add(1, Ordering::AcqRel);
+        while send_ipi(hart, ipi_msg::TLB_SHOOTDOWN(req)).is_err() {
+            poll_self(self_id);
+            spin_loop();
+        }
+        sent += 1;
+    }
+
+    if sent != 0 {
+        wait_acks(self_id);
+    }
+}
+
+/*
+ * M-mode runs with interrupts off, and so does S-mode with machine interrupts pushed off
+ * (CRITICAL task, Critical_Area). Shootdowns aimed at this hart are served by polling
+ * here then, otherwise two harts shooting at each other would wait forever.
+ *
+ * Polling with M_off_depth != 0 can't deadlock against a hart waiting on us: the only
+ * thing it waits for is our ack, and serving a shootdown needs nothing but our own
+ * mailbox lock, which no holder keeps across a wait, and a local sfence. Whatever
+ * locks we hold don't matter to it. CALL and HALT are never run from S-mode here, so
+ * polling itself never starts anything that could wait on another hart
+ */
+fn wait_acks(self_id: usize) {
+    while PENDING_ACK[self_id].load(Ordering::Acquire) != 0 {
+        poll_self(self_id);
+        spin_loop();
+    }
+}
+
+fn poll_self(self_id: usize) {
+    if matches!(get_cpu_mode(self_id), Mode::Machine | Mode::Machine_IRH)
+        || M_off_depth(self_id) != 0
+    {
+        ipi_poll(self_id);
+    }
+}
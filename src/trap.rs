@@ -1,10 +1,22 @@
-use crate::cpu::{busy_delay, set_cpu_mode, which_cpu, M_cli, M_sti, Mode, TrapFrame};
+use crate::cpu::{
+    busy_delay, set_cpu_mode, which_cpu, M_cli, M_pop_off, M_push_off, M_sti, Mode,
+    S_defer_resched, S_off_depth, TrapFrame,
+};
+use crate::ecall::U2Sop;
+use crate::error::{KError, KErrorType};
+use crate::ipc::{ipc_result, set_syscall_ret, IPC_ERR, IPC_OK};
+use crate::ipi::ipi_handler;
 use crate::irq::{int_request, int_type};
 use crate::ktask::ktask_extint;
-use crate::kthread::INVAL_KTHREADS_PID;
 use crate::kthread::{task_flag, task_pool, task_state, task_struct};
+use crate::kthread::{EXIT_KILLED, INVAL_KTHREADS_PID, WAIT_DONE, WAIT_ERR};
+use crate::ktimer::timer_tick;
+use crate::new_kerror;
+use crate::pid::task_handle;
 use crate::plic;
+use crate::plic::extint_name;
 use crate::sem_uart;
+use crate::vm::EntryBits;
 use crate::Mprintln;
 use crate::EXTINT_SRCS;
 use crate::IRQ_BUFFER;
@@ -30,7 +42,7 @@ extern "C" fn s_trap(
     set_cpu_mode(Mode::Supervisor, hart);
     let spp: Mode = sstatus::read().spp().into();
 
-    let is_async = if xcause >> 63 & 1 == 1 { true } else { false };
+    let is_async = (xcause >> 63) & 1 == 1;
 
     let cause_num = xcause & 0xfff;
     let mut pc_ret = xepc;
@@ -69,7 +81,7 @@ extern "C" fn m_trap(
     set_cpu_mode(Mode::Machine_IRH, hart);
     let mpp: Mode = mstatus::read().mpp().into();
 
-    let is_async = if xcause >> 63 & 1 == 1 { true } else { false };
+    let is_async = (xcause >> 63) & 1 == 1;
 
     let cause_num = xcause & 0xfff;
     let mut pc_ret: usize = xepc;
@@ -79,12 +91,13 @@ extern "C" fn m_trap(
     if is_async {
         match cause_num {
             3 => {
-                Mprintln!("Machine SW Interrupt at CPU#{}", hart);
+                if ipi_handler(hart) {
+                    preempt_handler(xepc, hart);
+                }
             }
             7 => {
-                Mprintln!("Machine Timer Interrupt at CPU#{}", hart);
-                unsafe {
-                    CLINT.set_mtimecmp(hart, CLINT.read_mtime() + 0x500_000);
+                if timer_tick(hart) {
+                    preempt_handler(xepc, hart);
                 }
             }
             11 => {
@@ -92,8 +105,13 @@ extern "C" fn m_trap(
                     let current_ctx = plic::id2plic_ctx(hart);
                     let extint_id = PLIC.claim(&current_ctx).unwrap_or(60);
                     let mut data: Option<usize> = None;
-                    match extint_id {
-                        10 => {
+                    let src_name = EXTINT_SRCS
+                        .read()
+                        .get(extint_id as usize)
+                        .map(|src| *src.get_name())
+                        .unwrap_or(extint_name::UNDEF);
+                    match src_name {
+                        extint_name::UART0 => {
                             let ch_get = M_UART.lock().get();
                             if let Some(ch) = ch_get {
                                 data = Some(ch as usize);
@@ -101,7 +119,7 @@ extern "C" fn m_trap(
                                 data = None;
                             }
                         }
-                        0 => {
+                        extint_name::UNDEF if extint_id == 0 => {
                             //do nothing when 0
                         }
                         _ => {
@@ -166,7 +184,7 @@ extern "C" fn m_trap(
                 cdump_flag = true;
             }
             8 => {
-                Mprintln!("E-call from User mode at CPU#{}", hart);
+                syscall_handler(pc_ret, hart, frame);
                 pc_ret += 4;
             }
             9 => {
@@ -179,6 +197,9 @@ extern "C" fn m_trap(
                 ecall_handler(pc_ret, hart);
                 pc_ret += 4;
             }
+            12 | 13 | 15 if matches!(mpp, Mode::User) => {
+                upage_fault_handler(xtval, cause_num, hart);
+            }
             12 => {
                 Mprintln!("Instruction page fault at CPU#{}", hart);
                 cdump_flag = true;
@@ -197,7 +218,7 @@ extern "C" fn m_trap(
             }
         }
 
-        if cdump_flag == true {
+        if cdump_flag {
             Mprintln!(
                 "
 >>>>>>Core Dump<<<<<<
@@ -223,6 +244,140 @@ satp: 0x{:x}
     pc_ret
 }
 
+/*
+ * Time slice of current task is used up, or another hart asked for a reschedule. An idle
+ * hart looks for work and goes back to wfi when there's none. Nothing happens in early
+ * boot or when current task is CRITICAL. A task holding an S-mode lock is left running
+ * until it drops the lock, see S_pop_off()
+ */
+fn preempt_handler(pc_ret: usize, hart: usize) {
+    unsafe {
+        if !KTHREAD_POOL.is_on_task(hart) {
+            if KTHREAD_POOL.is_idle(hart) {
+                KTHREAD_POOL.sched(hart);
+            }
+            return;
+        }
+
+        if let Ok(task_flag::CRITICAL) = KTHREAD_POOL.get_current_fg(hart) {
+            return;
+        }
+
+        if S_off_depth(hart) != 0 {
+            S_defer_resched(hart);
+            return;
+        }
+
+        KTHREAD_POOL.save_from_ktrapframe(hart);
+        KTHREAD_POOL.set_currentPC(hart, pc_ret);
+        KTHREAD_POOL.sched(hart);
+        KTHREAD_POOL.fallback(hart);
+    }
+}
+
+/*
+ * Page faults from U-mode. A fault inside one of the task's vmas gets a fresh page and
+ * the instruction is retried, anything else kills the task instead of the kernel
+ */
+fn upage_fault_handler(fault_addr: usize, cause_num: usize, hart: usize) {
+    let access = match cause_num {
+        12 => EntryBits::Execute,
+        13 => EntryBits::Read,
+        _ => EntryBits::Write,
+    };
+
+    unsafe {
+        let res = match KTHREAD_POOL.current_mut(hart) {
+            Some(task) => task.get_mm().fault_in(fault_addr, access.val()),
+            None => Err(new_kerror!(KErrorType::EFAULT)),
+        };
+
+        if let Err(e) = res {
+            Mprintln!(
+                "Killed user task at CPU#{}: page fault at {:#x}, {}",
+                hart,
+                fault_addr,
+                e
+            );
+            KTHREAD_POOL.exit_current(hart, EXIT_KILLED);
+            KTHREAD_POOL.sched(hart);
+            KTHREAD_POOL.fallback(hart);
+        }
+    }
+}
+
+/*
+ * Syscalls from U-mode, see U2Sop for the register ABI. A call that blocks never returns
+ * here, its result is put into the task's own trap frame when it's woken up
+ */
+fn syscall_handler(pc_ret: usize, hart: usize, frame: &mut TrapFrame) {
+    let opcode: U2Sop = frame.regs[17].into();
+    let peer = (frame.regs[10], frame.regs[11]);
+    let msg_addr = frame.regs[12];
+
+    unsafe {
+        KTHREAD_POOL.save_from_ktrapframe(hart);
+        KTHREAD_POOL.set_currentPC(hart, pc_ret + 4);
+
+        let res = match opcode {
+            U2Sop::SEND => KTHREAD_POOL
+                .ipc_send(hart, peer, msg_addr, false)
+                .map(ipc_ret),
+            U2Sop::RECV => KTHREAD_POOL.ipc_recv(hart, peer, msg_addr).map(ipc_ret),
+            U2Sop::SEND_RECV => KTHREAD_POOL
+                .ipc_send(hart, peer, msg_addr, true)
+                .map(ipc_ret),
+            U2Sop::FORK => KTHREAD_POOL.fork(hart).map(|child| {
+                frame.regs[11] = child.lifeid();
+                Some(child.pid())
+            }),
+            U2Sop::EXIT => KTHREAD_POOL
+                .exit_current(hart, frame.regs[10])
+                .map(|_| None),
+            U2Sop::WAIT => KTHREAD_POOL.wait_child(hart, peer.into(), 0),
+            U2Sop::UNDEF => {
+                Mprintln!("Undefined syscall #{} at CPU#{}", frame.regs[17], hart);
+                Err(new_kerror!(KErrorType::ENOSYS))
+            }
+        };
+
+        match res {
+            Ok(Some(ret)) => {
+                set_syscall_ret(frame, ret);
+            }
+            Ok(None) => {
+                KTHREAD_POOL.sched(hart);
+                KTHREAD_POOL.fallback(hart);
+            }
+            Err(_) => {
+                set_syscall_ret(frame, IPC_ERR);
+            }
+        }
+    }
+}
+
+/*
+ * None means current task is blocked and should be switched out
+ */
+fn ipc_ret(res: ipc_result) -> Option<usize> {
+    match res {
+        ipc_result::Done => Some(IPC_OK),
+        ipc_result::Blocked => None,
+    }
+}
+
+/*
+ * CRITICAL task runs with machine interrupts pushed off, they come back once it leaves
+ * the hart for good or to sleep. sched() pushes them off again when it resumes one
+ */
+fn crit_task_leave(hart: usize) {
+    unsafe {
+        if let Ok(task_flag::CRITICAL) = KTHREAD_POOL.get_current_fg(hart) {
+            M_pop_off();
+        }
+    }
+}
+
 fn ecall_handler(pc_ret: usize, hart: usize) {
     unsafe {
         let opcode = SECALL_FRAME[hart].get_opcode();
@@ -231,25 +386,21 @@ fn ecall_handler(pc_ret: usize, hart: usize) {
                 panic!("Supervisor is tring to call undefined operation");
             }
             S2Mop::YIELD => {
-                if let Ok(task_flag::CRITICAL) = KTHREAD_POOL.get_current_fg(hart) {
-                    let prev_mie = KTHREAD_POOL.get_crit_task_mie();
-                    M_sti(prev_mie[hart]);
-                }
+                crit_task_leave(hart);
                 KTHREAD_POOL.save_from_ktrapframe(hart);
                 KTHREAD_POOL.set_currentPC(hart, pc_ret + 4);
                 KTHREAD_POOL.sched(hart);
                 KTHREAD_POOL.fallback(hart);
             }
             S2Mop::EXIT => {
-                KTHREAD_POOL.remove_cur_task(hart);
+                crit_task_leave(hart);
+                let code = SECALL_FRAME[hart].get_args()[0];
+                KTHREAD_POOL.exit_current(hart, code);
                 KTHREAD_POOL.sched(hart);
                 KTHREAD_POOL.fallback(hart);
             }
             S2Mop::BLOCK => {
-                if let Ok(task_flag::CRITICAL) = KTHREAD_POOL.get_current_fg(hart) {
-                    let prev_mie = KTHREAD_POOL.get_crit_task_mie();
-                    M_sti(prev_mie[hart]);
-                }
+                crit_task_leave(hart);
                 let args = SECALL_FRAME[hart].get_args();
                 let target_pid = args[0];
                 let target_lifeid = args[1];
@@ -274,15 +425,64 @@ fn ecall_handler(pc_ret: usize, hart: usize) {
                 KTHREAD_POOL.save_from_ktrapframe(hart);
                 KTHREAD_POOL.set_currentPC(hart, pc_ret + 4);
 
-                KTHREAD_POOL.set_state_by_pid(target_pid, target_lifeid, task_state::Ready);
+                KTHREAD_POOL.wake_committed(target_pid, target_lifeid);
             }
             S2Mop::CLI => {
-                let prev_mie = M_cli();
-                KERNEL_TRAP_FRAME[hart].mie_buf = prev_mie;
+                M_push_off();
             }
             S2Mop::STI => {
-                let prev_mie = KERNEL_TRAP_FRAME[hart].mie_buf;
-                M_sti(prev_mie);
+                M_pop_off();
+            }
+            S2Mop::SETPRIO => {
+                let args = SECALL_FRAME[hart].get_args();
+                let ret = match KTHREAD_POOL.change_prio(args[0], args[1], args[2]) {
+                    Ok(()) => 0,
+                    Err(_) => usize::MAX,
+                };
+                SECALL_FRAME[hart].set_ret(ret);
+            }
+            S2Mop::SLEEP => {
+                let deadline = SECALL_FRAME[hart].get_args()[0] as u64;
+
+                KTHREAD_POOL.save_from_ktrapframe(hart);
+                KTHREAD_POOL.set_currentPC(hart, pc_ret + 4);
+
+                if let Ok(true) = KTHREAD_POOL.sleep_current(hart, deadline) {
+                    crit_task_leave(hart);
+                    KTHREAD_POOL.sched(hart);
+                    KTHREAD_POOL.fallback(hart);
+                }
+            }
+            S2Mop::WAIT => {
+                let args = SECALL_FRAME[hart].get_args();
+                let target = task_handle::new(args[0], args[1]);
+                let res = args[2] as *mut usize;
+
+                KTHREAD_POOL.save_from_ktrapframe(hart);
+                KTHREAD_POOL.set_currentPC(hart, pc_ret + 4);
+
+                match KTHREAD_POOL.wait_child(hart, target, res as usize) {
+                    Ok(Some(code)) => {
+                        res.add(1).write_volatile(code);
+                        res.write_volatile(WAIT_DONE);
+                    }
+                    Ok(None) => {
+                        crit_task_leave(hart);
+                        KTHREAD_POOL.sched(hart);
+                        KTHREAD_POOL.fallback(hart);
+                    }
+                    Err(_) => {
+                        res.write_volatile(WAIT_ERR);
+                    }
+                }
+            }
+            S2Mop::SETAFFINITY => {
+                let args = SECALL_FRAME[hart].get_args();
+                let ret = match KTHREAD_POOL.set_affinity(args[0], args[1], args[2]) {
+                    Ok(()) => 0,
+                    Err(_) => usize::MAX,
+                };
+                SECALL_FRAME[hart].set_ret(ret);
             }
         }
     }
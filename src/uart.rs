@@ -22,6 +22,10 @@ impl Uart {
         Uart { base_address }
     }
 
+    pub fn set_base(&mut self, new_base: usize) {
+        self.base_address = new_base;
+    }
+
     pub fn init(&mut self) {
         let ptr = self.base_address as *mut u8;
         unsafe {
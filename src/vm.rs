@@ -1,15 +1,21 @@
 use crate::alloc::collections::BTreeMap;
-use crate::cpu::flush_tlb;
+use crate::asid::{ctx2asid, ASID_ALLOC};
+use crate::cpu::{make_satp, SATP_mode};
 use crate::error::{KError, KErrorType};
+use crate::kmem::get_page_table;
 use crate::new_kerror;
 use crate::page;
-use crate::zone::{kfree_page, kmalloc_page, zone_type};
+use crate::tlb::{flush_local, tlb_shootdown};
+use crate::zone::{kfree_page, kget_page, kmalloc_page, kpage_refcnt, zone_type};
 use crate::{aligh_4k, aligl_4k};
 use crate::{M_UART, S_UART};
 use alloc::collections::btree_map::Entry;
 use alloc::vec::Vec;
+use core::ptr;
 use get_set_macro::get_set;
 
+pub const USER_VA_END: usize = 0x40_0000_0000;
+
 pub struct PageTable {
     pub entries: [PageEntry; 512],
 }
@@ -30,6 +36,10 @@ pub enum EntryBits {
     Global = 1 << 5,
     Access = 1 << 6,
     Dirty = 1 << 7,
+    /*
+     * RSW bit, write protected page shared by fork(), see mm::fault_in()
+     */
+    Cow = 1 << 8,
 
     // Convenience combinations
     ReadWrite = 1 << 1 | 1 << 2,
@@ -113,6 +123,9 @@ pub fn mem_map(
     for i in (level..2).rev() {
         if !v.is_valid() {
             let page = kmalloc_page(zone_type::ZONE_NORMAL, 1)?;
+            unsafe {
+                page.write_bytes(0, page::PAGE_SIZE);
+            }
 
             v.set_entry(((page as i64) >> 2) | EntryBits::Valid.val());
         }
@@ -131,6 +144,10 @@ pub fn mem_map(
     Ok(())
 }
 
+/*
+ * mem_map()/mem_unmap() only edit page table, caller is responsible for tlb_shootdown()
+ * once the whole range is done
+ */
 pub fn mem_unmap(root: &mut PageTable, vaddr: usize, level: usize) -> Result<(), KError> {
     let vpn = [
         (vaddr >> 12) & 0x1ff,
@@ -146,7 +163,6 @@ pub fn mem_unmap(root: &mut PageTable, vaddr: usize, level: usize) -> Result<(),
         } else if v.is_leaf() {
             let new_ent = v.get_entry();
             v.set_entry(new_ent & !EntryBits::Valid.val());
-            flush_tlb();
 
             return Ok(());
         }
@@ -158,6 +174,33 @@ pub fn mem_unmap(root: &mut PageTable, vaddr: usize, level: usize) -> Result<(),
     Ok(())
 }
 
+/*
+ * Level 0 leaf entry of vaddr, None when any level on the way is missing
+ */
+pub fn leaf_entry(root: &mut PageTable, vaddr: usize) -> Option<&mut PageEntry> {
+    let vpn = [
+        (vaddr >> 12) & 0x1ff,
+        (vaddr >> 21) & 0x1ff,
+        (vaddr >> 30) & 0x1ff,
+    ];
+
+    let mut v = &mut root.entries[vpn[2]];
+    for i in (0..2).rev() {
+        if v.is_invalid() || v.is_leaf() {
+            return None;
+        }
+
+        let entry = ((v.get_entry() & !0x3ff) << 2) as *mut PageEntry;
+        v = unsafe { entry.add(vpn[i]).as_mut().unwrap() };
+    }
+
+    if v.is_valid() {
+        Some(v)
+    } else {
+        None
+    }
+}
+
 pub fn virt2phys(root: &PageTable, vaddr: usize) -> Result<Option<usize>, KError> {
     let vpn = [
         (vaddr >> 12) & 0x1ff,
@@ -185,6 +228,89 @@ pub fn virt2phys(root: &PageTable, vaddr: usize) -> Result<Option<usize>, KError
     Ok(None)
 }
 
+/*
+ * User page table starts as a copy of kernel root, so every top level entry of kernel is
+ * shared(without U bit). User space has to stay away from those 1GiB slots
+ */
+pub fn new_user_pgtable() -> Result<*mut PageTable, KError> {
+    let new_root = kmalloc_page(zone_type::ZONE_NORMAL, 1)? as *mut PageTable;
+    let kern_root = unsafe { get_page_table().as_ref().unwrap() };
+
+    unsafe {
+        new_root.write_bytes(0, 1);
+        let root = new_root.as_mut().unwrap();
+        for (idx, kern_ent) in kern_root.entries.iter().enumerate() {
+            root.entries[idx].set_entry(kern_ent.get_entry());
+        }
+    }
+
+    Ok(new_root)
+}
+
+/*
+ * [begin, end) must sit in lower half of Sv39 and must not touch any 1GiB slot which
+ * kernel root already uses
+ */
+pub fn is_user_range(begin: usize, end: usize) -> bool {
+    if begin >= end || end > USER_VA_END {
+        return false;
+    }
+
+    let kern_root = unsafe { get_page_table().as_ref().unwrap() };
+    for slot in (begin >> 30)..=((end - 1) >> 30) {
+        if kern_root.entries[slot].is_valid() {
+            return false;
+        }
+    }
+
+    true
+}
+
+fn is_shared_with_kern(idx: usize, ent: &PageEntry) -> bool {
+    let kern_root = unsafe { get_page_table().as_ref().unwrap() };
+    kern_root.entries[idx].is_valid() && kern_root.entries[idx].get_entry() == ent.get_entry()
+}
+
+fn free_table_levels(table: *mut PageTable) {
+    let table_ref = unsafe { table.as_mut().unwrap() };
+    for ent in table_ref.entries.iter_mut() {
+        if ent.is_invalid() {
+            continue;
+        }
+
+        if ent.is_branch() {
+            let next_level = ((ent.get_entry() & !0x3ff) << 2) as *mut PageTable;
+            free_table_levels(next_level);
+        } else if ent.get_entry() & EntryBits::User.val() != 0 {
+            let leaf_page = ((ent.get_entry() & !0x3ff) << 2) as *mut u8;
+            kfree_page(zone_type::ZONE_NORMAL, leaf_page, 1);
+        }
+        ent.set_entry(0);
+    }
+
+    kfree_page(zone_type::ZONE_NORMAL, table as *mut u8, 1);
+}
+
+/*
+ * Page table pages and user leaf pages(U bit set) below it are released. Top level
+ * entries borrowed from kernel root are skipped
+ */
+pub fn free_user_pgtable(root: &mut PageTable) {
+    for (idx, ent) in root.entries.iter_mut().enumerate() {
+        if ent.is_invalid() || is_shared_with_kern(idx, ent) {
+            continue;
+        }
+
+        if ent.is_branch() {
+            let next_level = ((ent.get_entry() & !0x3ff) << 2) as *mut PageTable;
+            free_table_levels(next_level);
+        }
+        ent.set_entry(0);
+    }
+
+    kfree_page(zone_type::ZONE_NORMAL, root as *mut PageTable as *mut u8, 1);
+}
+
 pub fn ident_range_map(
     root: &mut PageTable,
     begin: usize,
@@ -207,6 +333,12 @@ pub fn ident_range_map(
         addr_begin += page::PAGE_SIZE;
     }
 
+    /*
+     * Only mappings which went away or lost permission need a shootdown, a new mapping
+     * is flushed locally
+     */
+    flush_local(None, begin, end);
+
     Ok(())
 }
 
@@ -223,12 +355,14 @@ pub fn range_unmap(root: &mut PageTable, begin: usize, end: usize) -> Result<(),
         addr_begin += page::PAGE_SIZE;
     }
 
+    tlb_shootdown(root as *mut PageTable as usize, None, begin, end);
+
     Ok(())
 }
 
 #[get_set(default(inline_always, vis = "pub"), get_copy, set)]
 #[derive(Clone, Copy, PartialEq, Eq)]
-struct vm_area {
+pub struct vm_area {
     vm_begin: usize,
     vm_end: usize,
     flags: usize,
@@ -251,12 +385,16 @@ impl vm_area {
 }
 
 #[get_set(default(inline_always, vis = "pub"), get_copy, set)]
-struct mm {
+pub struct mm {
     #[gsflags(skip)]
     vmas: Option<BTreeMap<usize, vm_area>>,
 
     pgroot_addr: usize,
     satp: u64,
+    /*
+     * ASID context from asid_allocator, ASID part is mirrored in satp
+     */
+    asid_ctx: usize,
 
     heap_end: usize,
     stack_base: usize,
@@ -268,29 +406,27 @@ impl mm {
             vmas: None,
             pgroot_addr: 0,
             satp: 0,
+            asid_ctx: 0,
             heap_end: 0,
             stack_base: 0,
         }
     }
 
     pub fn has_vma(&self, target: vm_area) -> bool {
-        if let None = self.vmas {
-            false
-        } else {
-            match self.vmas.as_ref().unwrap().get(&target.vm_begin) {
-                Some(found_vma) => {
-                    if found_vma == &target {
-                        true
-                    } else {
-                        false
-                    }
-                }
-                None => false,
-            }
+        match self.vmas.as_ref() {
+            Some(vmas) => vmas.get(&target.vm_begin) == Some(&target),
+            None => false,
         }
     }
 
+    /*
+     * [begin, end) may not overlap with any existing vma
+     */
     pub fn insert_vma(&mut self, new_vma: vm_area) -> Result<(), KError> {
+        if new_vma.vm_begin >= new_vma.vm_end || self.overlaps(new_vma.vm_begin, new_vma.vm_end) {
+            return Err(new_kerror!(KErrorType::EFAULT));
+        }
+
         let vmas = self.vmas.get_or_insert_with(BTreeMap::new);
 
         match vmas.entry(new_vma.vm_begin) {
@@ -302,11 +438,25 @@ impl mm {
         }
     }
 
+    /*
+     * vma which covers addr, not only the one begins at addr
+     */
     pub fn get_vma(&self, addr: usize) -> Option<&vm_area> {
-        if let None = self.vmas {
-            None
-        } else {
-            self.vmas.as_ref().unwrap().get(&addr)
+        self.vmas
+            .as_ref()?
+            .range(..=addr)
+            .next_back()
+            .map(|(_, vma)| vma)
+            .filter(|vma| addr < vma.vm_end)
+    }
+
+    pub fn overlaps(&self, begin: usize, end: usize) -> bool {
+        match self.vmas {
+            Some(ref vmas) => vmas
+                .range(..end)
+                .next_back()
+                .is_some_and(|(_, vma)| vma.vm_end > begin),
+            None => false,
         }
     }
 
@@ -318,4 +468,428 @@ impl mm {
             Err(new_kerror!(KErrorType::EFAULT))
         }
     }
+
+    /*
+     * Cut the vma covering addr into [vm_begin, addr) and [addr, vm_end), so that later
+     * range operations only need to deal with whole vmas
+     */
+    fn split_vma(&mut self, addr: usize) {
+        let vma = match self.get_vma(addr) {
+            Some(vma) if vma.vm_begin != addr => *vma,
+            _ => return,
+        };
+
+        let vmas = self.vmas.as_mut().unwrap();
+        let mut head = vma;
+        head.vm_end = addr;
+        let mut tail = vma;
+        tail.vm_begin = addr;
+
+        vmas.insert(head.vm_begin, head);
+        vmas.insert(tail.vm_begin, tail);
+    }
+
+    fn vmas_in(&self, begin: usize, end: usize) -> Vec<vm_area> {
+        match self.vmas {
+            Some(ref vmas) => vmas.range(begin..end).map(|(_, vma)| *vma).collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /*
+     * Drop every mapping inside [begin, end) and free the pages behind it, holes in
+     * the range are fine
+     */
+    pub fn unmap(&mut self, begin: usize, end: usize) -> Result<(), KError> {
+        let begin = aligl_4k!(begin);
+        let end = aligh_4k!(end);
+        if begin >= end {
+            return Err(new_kerror!(KErrorType::EINVAL));
+        }
+
+        self.split_vma(begin);
+        self.split_vma(end);
+
+        /*
+         * Pages are only freed after no hart can reach them through a stale TLB entry
+         */
+        let mut freed: Vec<usize> = Vec::new();
+        for vma in self.vmas_in(begin, end) {
+            for vaddr in (vma.vm_begin..vma.vm_end).step_by(page::PAGE_SIZE) {
+                let pgroot = self.pgroot()?;
+                if let Some(paddr) = virt2phys(pgroot, vaddr)? {
+                    mem_unmap(pgroot, vaddr, 0)?;
+                    freed.push(aligl_4k!(paddr));
+                }
+            }
+            self.delete_vma(vma)?;
+        }
+        self.flush(begin, end);
+
+        for paddr in freed {
+            kfree_page(zone_type::ZONE_NORMAL, paddr as *mut u8, 1);
+        }
+
+        Ok(())
+    }
+
+    /*
+     * Change permission of [begin, end), the whole range has to be covered by vmas
+     */
+    pub fn protect(&mut self, begin: usize, end: usize, bits: i64) -> Result<(), KError> {
+        let begin = aligl_4k!(begin);
+        let end = aligh_4k!(end);
+        if begin >= end || bits & EntryBits::ReadWriteExecute.val() == 0 {
+            return Err(new_kerror!(KErrorType::EINVAL));
+        }
+
+        let mut cursor = begin;
+        while cursor < end {
+            match self.get_vma(cursor) {
+                Some(vma) => cursor = vma.vm_end,
+                None => return Err(new_kerror!(KErrorType::EFAULT)),
+            }
+        }
+
+        self.split_vma(begin);
+        self.split_vma(end);
+
+        for mut vma in self.vmas_in(begin, end) {
+            for vaddr in (vma.vm_begin..vma.vm_end).step_by(page::PAGE_SIZE) {
+                if let Some(ent) = leaf_entry(self.pgroot()?, vaddr) {
+                    /*
+                     * Shared page stays read-only until fault_in() breaks the sharing. A page
+                     * fork() left read-only is shared without Cow, it turns Cow only once
+                     * write is granted here
+                     */
+                    let ppn_bits = ent.get_entry() & !0x3ff;
+                    let paddr = (ppn_bits << 2) as *mut u8;
+                    let shared = ent.get_entry() & EntryBits::Cow.val() != 0
+                        || (bits & EntryBits::Write.val() != 0
+                            && kpage_refcnt(zone_type::ZONE_NORMAL, paddr)? > 1);
+                    let mut new_bits = bits;
+                    if shared {
+                        new_bits = (bits & !EntryBits::Write.val()) | EntryBits::Cow.val();
+                    }
+                    ent.set_entry(ppn_bits | new_bits | EntryBits::Valid.val());
+                }
+            }
+            vma.flags = bits as usize;
+            self.vmas.as_mut().unwrap().insert(vma.vm_begin, vma);
+        }
+        self.flush(begin, end);
+
+        Ok(())
+    }
+
+    pub fn init_user(&mut self) -> Result<(), KError> {
+        let pgroot = new_user_pgtable()?;
+        self.pgroot_addr = pgroot as usize;
+        self.satp = make_satp(SATP_mode::Sv39, 0, self.pgroot_addr) as u64;
+        Ok(())
+    }
+
+    pub fn get_asid(&self) -> usize {
+        ((self.satp >> 44) & 0xffff) as usize
+    }
+
+    /*
+     * Called on the hart this address space is about to run on, returns satp to load
+     */
+    pub fn switch_asid(&mut self, hart: usize) -> u64 {
+        let new_ctx = ASID_ALLOC.lock().activate(hart, self.asid_ctx);
+        if new_ctx != self.asid_ctx {
+            self.asid_ctx = new_ctx;
+            self.satp = make_satp(SATP_mode::Sv39, ctx2asid(new_ctx), self.pgroot_addr) as u64;
+        }
+
+        self.satp
+    }
+
+    /*
+     * Shootdown of [begin, end) for this address space, 0..0 flushes all of it
+     */
+    fn flush(&self, begin: usize, end: usize) {
+        tlb_shootdown(self.pgroot_addr, Some(self.get_asid()), begin, end);
+    }
+
+    pub fn pgroot(&mut self) -> Result<&mut PageTable, KError> {
+        unsafe {
+            (self.pgroot_addr as *mut PageTable)
+                .as_mut()
+                .ok_or(new_kerror!(KErrorType::EFAULT))
+        }
+    }
+
+    /*
+     * New vma without any page behind it, pages come in one by one from fault_in()
+     */
+    pub fn map_lazy(&mut self, begin: usize, end: usize, bits: i64) -> Result<vm_area, KError> {
+        let mut new_vma = vm_area::new();
+        new_vma.init(begin, end, bits as usize);
+        self.insert_vma(new_vma)?;
+
+        Ok(new_vma)
+    }
+
+    /*
+     * New vma backed by zeroed pages right away, one page per allocation so that every
+     * page can be released on its own
+     */
+    pub fn map_range(&mut self, begin: usize, end: usize, bits: i64) -> Result<(), KError> {
+        let new_vma = self.map_lazy(begin, end, bits)?;
+
+        for vaddr in (new_vma.vm_begin..new_vma.vm_end).step_by(page::PAGE_SIZE) {
+            self.populate(vaddr)?;
+        }
+
+        Ok(())
+    }
+
+    /*
+     * Back the page holding vaddr with a zeroed page, using permission of its vma
+     */
+    fn populate(&mut self, vaddr: usize) -> Result<usize, KError> {
+        let vma = *self.get_vma(vaddr).ok_or(new_kerror!(KErrorType::EFAULT))?;
+
+        let page = kmalloc_page(zone_type::ZONE_NORMAL, 1)?;
+        unsafe {
+            page.write_bytes(0, page::PAGE_SIZE);
+        }
+
+        if let Err(e) = mem_map(
+            self.pgroot()?,
+            aligl_4k!(vaddr),
+            page as usize,
+            vma.flags as i64,
+            0,
+        ) {
+            kfree_page(zone_type::ZONE_NORMAL, page, 1);
+            return Err(e);
+        }
+
+        Ok(page as usize)
+    }
+
+    /*
+     * Page fault path. access is the EntryBits the faulting instruction needed. A store
+     * to a present page is only legal when the page is shared by fork(), any other fault
+     * on a present page is a real permission violation
+     */
+    pub fn fault_in(&mut self, vaddr: usize, access: i64) -> Result<(), KError> {
+        match self.check_access(vaddr, access)? {
+            Some(ent_bits) => {
+                if access & EntryBits::Write.val() == 0 || ent_bits & EntryBits::Cow.val() == 0 {
+                    return Err(new_kerror!(KErrorType::EFAULT));
+                }
+                self.break_cow(vaddr)?;
+            }
+            None => {
+                self.populate(vaddr)?;
+            }
+        }
+        self.flush(aligl_4k!(vaddr), aligl_4k!(vaddr) + page::PAGE_SIZE);
+
+        Ok(())
+    }
+
+    /*
+     * Last owner of a shared page simply gets write permission back, everyone else
+     * gets a private copy and drops one reference of the shared page
+     */
+    fn break_cow(&mut self, vaddr: usize) -> Result<usize, KError> {
+        let vma = *self.get_vma(vaddr).ok_or(new_kerror!(KErrorType::EFAULT))?;
+        let ent = leaf_entry(self.pgroot()?, vaddr).ok_or(new_kerror!(KErrorType::EFAULT))?;
+        if ent.get_entry() & EntryBits::Cow.val() == 0 {
+            return Err(new_kerror!(KErrorType::EFAULT));
+        }
+
+        let old_page = ((ent.get_entry() & !0x3ff) << 2) as *mut u8;
+        if kpage_refcnt(zone_type::ZONE_NORMAL, old_page)? == 1 {
+            ent.set_entry((ent.get_entry() & !0x3ff) | vma.flags as i64 | EntryBits::Valid.val());
+            return Ok(old_page as usize);
+        }
+
+        let new_page = kmalloc_page(zone_type::ZONE_NORMAL, 1)?;
+        unsafe {
+            ptr::copy_nonoverlapping(old_page as *const u8, new_page, page::PAGE_SIZE);
+        }
+        ent.set_entry(((new_page as i64) >> 2) | vma.flags as i64 | EntryBits::Valid.val());
+        kfree_page(zone_type::ZONE_NORMAL, old_page, 1)?;
+
+        Ok(new_page as usize)
+    }
+
+    /*
+     * Permission check shared by page faults and kernel touching user memory. vaddr has to
+     * sit inside a user vma allowing access, a present page has to be a user page allowing
+     * it as well, unless it is shared by fork() and about to be written. Gives back leaf
+     * bits of a present page
+     */
+    fn check_access(&mut self, vaddr: usize, access: i64) -> Result<Option<i64>, KError> {
+        let vma = *self.get_vma(vaddr).ok_or(new_kerror!(KErrorType::EFAULT))?;
+        let need = access | EntryBits::User.val();
+        if (vma.flags as i64) & need != need {
+            return Err(new_kerror!(KErrorType::EFAULT));
+        }
+
+        let ent_bits = match leaf_entry(self.pgroot()?, vaddr) {
+            Some(ent) => ent.get_entry(),
+            None => return Ok(None),
+        };
+
+        let cow_write =
+            access & EntryBits::Write.val() != 0 && ent_bits & EntryBits::Cow.val() != 0;
+        if ent_bits & EntryBits::User.val() == 0 || (!cow_write && ent_bits & need != need) {
+            return Err(new_kerror!(KErrorType::EFAULT));
+        }
+
+        Ok(Some(ent_bits))
+    }
+
+    /*
+     * Physical address kernel should use to touch vaddr on behalf of user. Missing pages
+     * are faulted in, shared pages are broken first when kernel is about to write
+     */
+    fn user_paddr(&mut self, vaddr: usize, write: bool, access: i64) -> Result<usize, KError> {
+        let pg_off = vaddr & (page::PAGE_SIZE - 1);
+        let ent_bits = match self.check_access(vaddr, access)? {
+            Some(ent_bits) => ent_bits,
+            None => return Ok(self.populate(vaddr)? | pg_off),
+        };
+
+        if write && ent_bits & EntryBits::Cow.val() != 0 {
+            let new_page = self.break_cow(vaddr)?;
+            self.flush(aligl_4k!(vaddr), aligl_4k!(vaddr) + page::PAGE_SIZE);
+            return Ok(new_page | pg_off);
+        }
+
+        Ok((((ent_bits & !0x3ff) << 2) as usize) | pg_off)
+    }
+
+    /*
+     * Kernel reaches user pages through identity map of ZONE_NORMAL, so the copy goes
+     * to physical address page by page. copy_fn gets (paddr, offset into buffer, length)
+     */
+    fn copy_user<F>(
+        &mut self,
+        vaddr: usize,
+        len: usize,
+        write: bool,
+        access: i64,
+        mut copy_fn: F,
+    ) -> Result<(), KError>
+    where
+        F: FnMut(usize, usize, usize),
+    {
+        match vaddr.checked_add(len) {
+            Some(end) if len == 0 || is_user_range(vaddr, end) => {}
+            _ => return Err(new_kerror!(KErrorType::EFAULT)),
+        }
+
+        let mut copied = 0;
+        while copied < len {
+            let cur_vaddr = vaddr + copied;
+            let paddr = self.user_paddr(cur_vaddr, write, access)?;
+            let chunk = (page::PAGE_SIZE - (cur_vaddr & (page::PAGE_SIZE - 1))).min(len - copied);
+
+            copy_fn(paddr, copied, chunk);
+            copied += chunk;
+        }
+
+        Ok(())
+    }
+
+    /*
+     * Duplicate parent's address space into self, which must be empty. Every present user
+     * page ends up shared, writable ones are write protected on both sides
+     */
+    pub fn fork_from(&mut self, parent: &mut mm) -> Result<(), KError> {
+        self.init_user()?;
+        self.heap_end = parent.heap_end;
+        self.stack_base = parent.stack_base;
+
+        let vmas = parent.vmas_in(0, USER_VA_END);
+        for vma in vmas {
+            self.insert_vma(vma)?;
+
+            for vaddr in (vma.vm_begin..vma.vm_end).step_by(page::PAGE_SIZE) {
+                let ent = match leaf_entry(parent.pgroot()?, vaddr) {
+                    Some(ent) => ent,
+                    None => continue,
+                };
+
+                let mut bits = ent.get_entry() & 0x3ff & !EntryBits::Valid.val();
+                if bits & EntryBits::Write.val() != 0 {
+                    bits = (bits & !EntryBits::Write.val()) | EntryBits::Cow.val();
+                    ent.set_entry((ent.get_entry() & !0x3ff) | bits | EntryBits::Valid.val());
+                }
+
+                let paddr = ((ent.get_entry() & !0x3ff) << 2) as usize;
+                kget_page(zone_type::ZONE_NORMAL, paddr as *mut u8)?;
+                mem_map(self.pgroot()?, vaddr, paddr, bits, 0)?;
+            }
+        }
+        parent.flush(0, 0);
+
+        Ok(())
+    }
+
+    /*
+     * Copies on behalf of user, target has to be writable by user. Pages not touched yet
+     * are faulted in here
+     */
+    pub fn copy_to_user(&mut self, vaddr: usize, src: &[u8]) -> Result<(), KError> {
+        self.copy_user(
+            vaddr,
+            src.len(),
+            true,
+            EntryBits::Write.val(),
+            |paddr, off, chunk| unsafe {
+                ptr::copy_nonoverlapping(src.as_ptr().add(off), paddr as *mut u8, chunk);
+            },
+        )
+    }
+
+    pub fn copy_from_user(&mut self, vaddr: usize, dst: &mut [u8]) -> Result<(), KError> {
+        let dst_ptr = dst.as_mut_ptr();
+        self.copy_user(
+            vaddr,
+            dst.len(),
+            false,
+            EntryBits::Read.val(),
+            |paddr, off, chunk| unsafe {
+                ptr::copy_nonoverlapping(paddr as *const u8, dst_ptr.add(off), chunk);
+            },
+        )
+    }
+
+    /*
+     * Fill an image which is still being built, e.g. text of a new task. Any user vma
+     * will do, whatever permission user is going to get
+     */
+    pub fn load_user(&mut self, vaddr: usize, src: &[u8]) -> Result<(), KError> {
+        self.copy_user(vaddr, src.len(), true, 0, |paddr, off, chunk| unsafe {
+            ptr::copy_nonoverlapping(src.as_ptr().add(off), paddr as *mut u8, chunk);
+        })
+    }
+
+    /*
+     * Tear down the whole address space, every table level and every user page goes back
+     * to page allocator
+     */
+    pub fn release(&mut self) {
+        if self.pgroot_addr == 0 {
+            return;
+        }
+
+        let pgroot = unsafe { &mut *(self.pgroot_addr as *mut PageTable) };
+        self.vmas = None;
+        self.flush(0, 0);
+
+        free_user_pgtable(pgroot);
+        self.pgroot_addr = 0;
+        self.satp = 0;
+        self.asid_ctx = 0;
+    }
 }
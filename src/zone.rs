@@ -7,16 +7,18 @@ use crate::new_kerror;
 use crate::SYS_ZONES;
 use crate::{M_UART, S_UART};
 
-use crate::page::{empty_allocator, naive_allocator};
+use crate::page::{buddy_allocator, empty_allocator, naive_allocator};
 
 pub enum AllocatorSelector {
     EmptyAllocator,
     NaiveAllocator,
+    BuddyAllocator,
 }
 
 pub enum Allocators {
     EmptyAllocator(empty_allocator),
     NaiveAllocator(naive_allocator),
+    BuddyAllocator(buddy_allocator),
 }
 
 impl page_allocator for Allocators {
@@ -33,6 +35,9 @@ impl page_allocator for Allocators {
             Allocators::NaiveAllocator(alloc) => {
                 alloc.allocator_init(zone_start, zone_end, zone_size)
             }
+            Allocators::BuddyAllocator(alloc) => {
+                alloc.allocator_init(zone_start, zone_end, zone_size)
+            }
         }
     }
 
@@ -40,12 +45,35 @@ impl page_allocator for Allocators {
         match self {
             Allocators::EmptyAllocator(alloc) => alloc.alloc_pages(pg_cnt),
             Allocators::NaiveAllocator(alloc) => alloc.alloc_pages(pg_cnt),
+            Allocators::BuddyAllocator(alloc) => alloc.alloc_pages(pg_cnt),
+        }
+    }
+    fn free_pages(&mut self, addr: *mut u8, pg_cnt: usize) -> Result<(), KError> {
+        match self {
+            Allocators::EmptyAllocator(alloc) => alloc.free_pages(addr, pg_cnt),
+            Allocators::NaiveAllocator(alloc) => alloc.free_pages(addr, pg_cnt),
+            Allocators::BuddyAllocator(alloc) => alloc.free_pages(addr, pg_cnt),
+        }
+    }
+    fn reserve_pages(&mut self, begin: usize, end: usize) -> Result<(), KError> {
+        match self {
+            Allocators::EmptyAllocator(alloc) => alloc.reserve_pages(begin, end),
+            Allocators::NaiveAllocator(alloc) => alloc.reserve_pages(begin, end),
+            Allocators::BuddyAllocator(alloc) => alloc.reserve_pages(begin, end),
+        }
+    }
+    fn page_refcnt(&self, addr: *mut u8) -> Result<usize, KError> {
+        match self {
+            Allocators::EmptyAllocator(alloc) => alloc.page_refcnt(addr),
+            Allocators::NaiveAllocator(alloc) => alloc.page_refcnt(addr),
+            Allocators::BuddyAllocator(alloc) => alloc.page_refcnt(addr),
         }
     }
-    fn free_pages(&mut self, addr: *mut u8) -> Result<(), KError> {
+    fn get_page(&mut self, addr: *mut u8) -> Result<usize, KError> {
         match self {
-            Allocators::EmptyAllocator(alloc) => alloc.free_pages(addr),
-            Allocators::NaiveAllocator(alloc) => alloc.free_pages(addr),
+            Allocators::EmptyAllocator(alloc) => alloc.get_page(addr),
+            Allocators::NaiveAllocator(alloc) => alloc.get_page(addr),
+            Allocators::BuddyAllocator(alloc) => alloc.get_page(addr),
         }
     }
 }
@@ -85,7 +113,22 @@ pub trait page_allocator {
         zone_size: usize,
     ) -> Result<(usize, usize), KError>;
     fn alloc_pages(&mut self, pg_cnt: usize) -> Result<*mut u8, KError>;
-    fn free_pages(&mut self, addr: *mut u8) -> Result<(), KError>;
+    /*
+     * addr and pg_cnt must be exactly what alloc_pages() handed out, the whole run is
+     * released at once
+     */
+    fn free_pages(&mut self, addr: *mut u8, pg_cnt: usize) -> Result<(), KError>;
+    /*
+     * Mark [begin, end) as taken so it never gets handed out, only valid before the first
+     * alloc_pages() call
+     */
+    fn reserve_pages(&mut self, begin: usize, end: usize) -> Result<(), KError>;
+    /*
+     * Reference count of the run starting at addr, it is kept on the first page of a run
+     * only. get_page() takes one more reference, which is dropped again by free_pages()
+     */
+    fn page_refcnt(&self, addr: *mut u8) -> Result<usize, KError>;
+    fn get_page(&mut self, addr: *mut u8) -> Result<usize, KError>;
 }
 
 pub struct mem_zone {
@@ -117,11 +160,12 @@ impl mem_zone {
     ) -> Result<(usize, usize), KError> {
         self.begin_addr = _start as usize;
         self.end_addr = _end as usize;
-        self.zone_size = (unsafe { _end.offset_from(_start) }) as usize;
+        self.zone_size = self.end_addr - self.begin_addr;
         self.types = _type;
         let mut allocator = match allocator {
             AllocatorSelector::EmptyAllocator => Allocators::EmptyAllocator(empty_allocator::new()),
             AllocatorSelector::NaiveAllocator => Allocators::NaiveAllocator(naive_allocator::new()),
+            AllocatorSelector::BuddyAllocator => Allocators::BuddyAllocator(buddy_allocator::new()),
         };
         let (meta_begin, meta_end) =
             allocator.allocator_init(_start as usize, _end as usize, self.zone_size)?;
@@ -132,17 +176,17 @@ impl mem_zone {
 
     pub fn get_size(&self) -> Result<usize, KError> {
         if self.begin_addr > self.end_addr {
-            return Err(new_kerror!(KErrorType::ENOMEM));
+            Err(new_kerror!(KErrorType::ENOMEM))
         } else {
-            return Ok(self.end_addr - self.begin_addr);
+            Ok(self.end_addr - self.begin_addr)
         }
     }
 
     pub fn print_all(&self) {
         Mprintln!(
             "[ZONE INFO] Begin: {:#x} -> End: {:#x}  Size: {:#x}  Type: {:#?}",
-            self.begin_addr as usize,
-            self.end_addr as usize,
+            self.begin_addr,
+            self.end_addr,
             self.zone_size,
             self.types.as_str()
         );
@@ -156,19 +200,74 @@ impl mem_zone {
         }
     }
 
-    pub fn free_pages(&mut self, addr: *mut u8) -> Result<(), KError> {
+    pub fn free_pages(&mut self, addr: *mut u8, pg_cnt: usize) -> Result<(), KError> {
+        if let Some(ref mut alloc) = self.pg_allocator {
+            alloc.free_pages(addr, pg_cnt)
+        } else {
+            Err(new_kerror!(KErrorType::ENOSYS))
+        }
+    }
+
+    pub fn page_refcnt(&self, addr: *mut u8) -> Result<usize, KError> {
+        if let Some(ref alloc) = self.pg_allocator {
+            alloc.page_refcnt(addr)
+        } else {
+            Err(new_kerror!(KErrorType::ENOSYS))
+        }
+    }
+
+    pub fn get_page(&mut self, addr: *mut u8) -> Result<usize, KError> {
+        if let Some(ref mut alloc) = self.pg_allocator {
+            alloc.get_page(addr)
+        } else {
+            Err(new_kerror!(KErrorType::ENOSYS))
+        }
+    }
+}
+
+impl mem_zone {
+    /*
+     * Ranges outside of this zone are simply ignored, so caller can throw every reserved
+     * region at every zone
+     */
+    pub fn reserve_pages(&mut self, begin: usize, end: usize) -> Result<(), KError> {
+        let rsv_begin = begin.max(self.begin_addr);
+        let rsv_end = end.min(self.end_addr);
+        if rsv_begin >= rsv_end {
+            return Ok(());
+        }
+
+        Mprintln!(
+            "[ZONE INFO] Reserve: {:#x} -> {:#x} in {}",
+            rsv_begin,
+            rsv_end,
+            self.types.as_str()
+        );
+
         if let Some(ref mut alloc) = self.pg_allocator {
-            alloc.free_pages(addr)
+            alloc.reserve_pages(rsv_begin, rsv_end)
         } else {
             Err(new_kerror!(KErrorType::ENOSYS))
         }
     }
 }
 
+#[cfg_attr(feature = "lockdep", track_caller)]
 pub fn kmalloc_page(ztype: zone_type, pg_cnt: usize) -> Result<*mut u8, KError> {
-    SYS_ZONES[ztype.val()].lock().alloc_pages(pg_cnt)
+    SYS_ZONES[ztype.val()].write().alloc_pages(pg_cnt)
+}
+
+#[cfg_attr(feature = "lockdep", track_caller)]
+pub fn kfree_page(ztype: zone_type, addr: *mut u8, pg_cnt: usize) -> Result<(), KError> {
+    SYS_ZONES[ztype.val()].write().free_pages(addr, pg_cnt)
+}
+
+#[cfg_attr(feature = "lockdep", track_caller)]
+pub fn kget_page(ztype: zone_type, addr: *mut u8) -> Result<usize, KError> {
+    SYS_ZONES[ztype.val()].write().get_page(addr)
 }
 
-pub fn kfree_page(ztype: zone_type, addr: *mut u8) -> Result<(), KError> {
-    SYS_ZONES[ztype.val()].lock().free_pages(addr)
+#[cfg_attr(feature = "lockdep", track_caller)]
+pub fn kpage_refcnt(ztype: zone_type, addr: *mut u8) -> Result<usize, KError> {
+    SYS_ZONES[ztype.val()].read().page_refcnt(addr)
 }